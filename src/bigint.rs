@@ -0,0 +1,300 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Mul, Neg, Sub};
+use std::str::FromStr;
+
+/// Arbitrary-precision integer. The magnitude is stored as little-endian base 2^32
+/// digits without trailing zeros, so zero has no digits and is never negative.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    digits: Vec<u32>,
+}
+
+fn trim(mut digits: Vec<u32>) -> Vec<u32> {
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    digits
+}
+
+fn cmp_digits(a: &[u32], b: &[u32]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_digits(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        result.push(sum as u32);
+        carry = sum >> 32;
+    }
+    result.push(carry as u32);
+    trim(result)
+}
+
+/// Expects `a >= b`
+fn sub_digits(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, x) in a.iter().enumerate() {
+        let mut diff = *x as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        }
+        result.push(diff as u32);
+    }
+    assert!(borrow == 0, "Subtracting a larger magnitude");
+    trim(result)
+}
+
+fn mul_digits(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = vec![0u32; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, y) in b.iter().enumerate() {
+            let product = *x as u64 * *y as u64 + result[i + j] as u64 + carry;
+            result[i + j] = product as u32;
+            carry = product >> 32;
+        }
+        result[i + b.len()] = carry as u32;
+    }
+    trim(result)
+}
+
+fn divrem_small(a: &[u32], divisor: u32) -> (Vec<u32>, u32) {
+    let mut quotient = vec![0u32; a.len()];
+    let mut rem = 0u64;
+    for i in (0..a.len()).rev() {
+        let current = (rem << 32) | a[i] as u64;
+        quotient[i] = (current / divisor as u64) as u32;
+        rem = current % divisor as u64;
+    }
+    (trim(quotient), rem as u32)
+}
+
+/// Binary long division, expects a non-zero `b`
+fn divrem_digits(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if b.len() == 1 {
+        let (quotient, rem) = divrem_small(a, b[0]);
+        return (quotient, trim(vec![rem]))
+    }
+    let mut quotient = vec![0u32; a.len()];
+    let mut rem: Vec<u32> = Vec::new();
+    for bit in (0..a.len() * 32).rev() {
+        // rem = rem * 2 + bit
+        let mut carry = (a[bit / 32] >> (bit % 32)) & 1;
+        for digit in rem.iter_mut() {
+            let next_carry = *digit >> 31;
+            *digit = (*digit << 1) | carry;
+            carry = next_carry;
+        }
+        if carry != 0 {
+            rem.push(carry);
+        }
+        if cmp_digits(&rem, b) != Ordering::Less {
+            rem = sub_digits(&rem, b);
+            quotient[bit / 32] |= 1 << (bit % 32);
+        }
+    }
+    (trim(quotient), rem)
+}
+
+impl BigInt {
+    fn from_parts(negative: bool, digits: Vec<u32>) -> Self {
+        let digits = trim(digits);
+        Self {
+            negative: negative && !digits.is_empty(),
+            digits,
+        }
+    }
+
+    pub fn zero() -> Self {
+        Self::from_parts(false, vec![])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    /// Division rounding towards negative infinity, so the remainder has the
+    /// sign of the divisor. Returns `None` when dividing by zero.
+    pub fn div_mod_floor(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None
+        }
+        let (quotient, rem) = divrem_digits(&self.digits, &other.digits);
+        let quotient = BigInt::from_parts(self.negative != other.negative, quotient);
+        let rem = BigInt::from_parts(self.negative, rem);
+        if !rem.is_zero() && rem.negative != other.negative {
+            Some((&quotient - &BigInt::from(1), &rem + other))
+        } else {
+            Some((quotient, rem))
+        }
+    }
+}
+
+impl From<u64> for BigInt {
+    fn from(n: u64) -> Self {
+        Self::from_parts(false, vec![n as u32, (n >> 32) as u32])
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(n: i64) -> Self {
+        let mut result = BigInt::from(n.unsigned_abs());
+        result.negative = n < 0;
+        result
+    }
+}
+
+impl From<i32> for BigInt {
+    fn from(n: i32) -> Self {
+        BigInt::from(n as i64)
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_digits(&self.digits, &other.digits),
+            (true, true) => cmp_digits(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::from_parts(!self.negative, self.digits.clone())
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::from_parts(self.negative, add_digits(&self.digits, &other.digits))
+        }
+        match cmp_digits(&self.digits, &other.digits) {
+            Ordering::Less => BigInt::from_parts(other.negative, sub_digits(&other.digits, &self.digits)),
+            _ => BigInt::from_parts(self.negative, sub_digits(&self.digits, &other.digits)),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        BigInt::from_parts(self.negative != other.negative, mul_digits(&self.digits, &other.digits))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseBigIntError;
+
+impl FromStr for BigInt {
+    type Err = ParseBigIntError;
+
+    fn from_str(text: &str) -> Result<Self, ParseBigIntError> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(text) => (true, text),
+            None => (false, text),
+        };
+        if text.is_empty() {
+            return Err(ParseBigIntError)
+        }
+        let mut digits = Vec::new();
+        for x in text.chars() {
+            let digit = x.to_digit(10).ok_or(ParseBigIntError)?;
+            digits = add_digits(&mul_digits(&digits, &[10]), &[digit]);
+        }
+        Ok(BigInt::from_parts(negative, digits))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0")
+        }
+        let mut chunks = Vec::new();
+        let mut digits = self.digits.clone();
+        while !digits.is_empty() {
+            let (quotient, rem) = divrem_small(&digits, 1_000_000_000);
+            chunks.push(rem);
+            digits = quotient;
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.last().unwrap())?;
+        for chunk in chunks.iter().rev().skip(1) {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(text: &str) -> BigInt {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    pub fn arithmetic() {
+        let a = big("123456789012345678901234567890");
+        let b = big("-987654321098765432109876543210");
+        assert_eq!((&a + &b).to_string(), "-864197532086419753208641975320");
+        assert_eq!((&a - &b).to_string(), "1111111110111111111011111111100");
+        assert_eq!((&a * &b).to_string(), "-121932631137021795226185032733622923332237463801111263526900");
+        assert_eq!((&a - &a), BigInt::zero());
+        assert_eq!(big("-0"), BigInt::zero());
+        assert!(big("-5") < big("3") && big("-5") < big("-3") && big("18446744073709551616") > big("18446744073709551615"));
+    }
+
+    #[test]
+    pub fn division() {
+        let (q, r) = big("121932631137021795226185032733622923332237463801111263526907").div_mod_floor(&big("987654321098765432109876543210")).unwrap();
+        assert_eq!((q.to_string(), r.to_string()), ("123456789012345678901234567890".to_string(), "7".to_string()));
+
+        let div_mod = |a: i64, b: i64| {
+            let (q, r) = BigInt::from(a).div_mod_floor(&BigInt::from(b)).unwrap();
+            (q.to_string(), r.to_string())
+        };
+        assert_eq!(div_mod(7, 2), ("3".to_string(), "1".to_string()));
+        assert_eq!(div_mod(-7, 2), ("-4".to_string(), "1".to_string()));
+        assert_eq!(div_mod(7, -2), ("-4".to_string(), "-1".to_string()));
+        assert_eq!(div_mod(-7, -2), ("3".to_string(), "-1".to_string()));
+        assert_eq!(BigInt::from(1).div_mod_floor(&BigInt::zero()), None);
+    }
+}
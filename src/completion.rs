@@ -0,0 +1,176 @@
+//! Knuth-Bendix completion. Turns a set of equations into a terminating and
+//! confluent set of rules by orienting them with a reduction ordering and
+//! adding the critical pairs that are not joinable as new equations.
+
+use super::{Bindings, Expr, Loc, Rule, RuleSet, Strategy, DEFAULT_STEP_LIMIT, substitute_bindings};
+use super::confluence::critical_pairs;
+use super::primitives::Primitives;
+use super::termination::{self, Order};
+use super::theories::Theories;
+
+/// Why the completion stopped before producing a convergent set of rules
+#[derive(Debug, PartialEq)]
+pub enum Failure {
+    /// The ordering orients neither side of the equation towards the other
+    Unorientable(Expr, Expr),
+    /// There were still equations left after the given amount of steps
+    StepLimitExceeded,
+}
+
+/// The named rules produced by the completion, convergent unless it failed
+pub struct Completion {
+    pub rules: Vec<(String, Rule)>,
+    pub failure: Option<Failure>,
+}
+
+fn size(expr: &Expr) -> usize {
+    match expr {
+        Expr::Fun(_, args) => 1 + args.iter().map(size).sum::<usize>(),
+        Expr::Sym(_) | Expr::Var(_) | Expr::Num(_) => 1,
+    }
+}
+
+fn normalize(expr: &Expr, rules: &[(String, Rule)], primitives: &Primitives) -> Result<Expr, Failure> {
+    let theories = Theories::default();
+    let rules = RuleSet {
+        rules: rules.iter().map(|(name, rule)| (name.as_str(), rule)).collect(),
+        primitives,
+        theories: &theories,
+        condition_depth: Default::default(),
+    };
+    Strategy::Innermost(DEFAULT_STEP_LIMIT).apply(expr, &mut |expr| rules.rewrite(expr))
+        .expect("Completion only produces rules without conditions")
+        .ok_or(Failure::StepLimitExceeded)
+}
+
+/// Turns the equation into a rule that keeps all the `rules` terminating under `order`
+fn orient(order: Order, rules: &[(String, Rule)], lhs: Expr, rhs: Expr, loc: &Loc) -> Result<Rule, Failure> {
+    for (head, body) in [(lhs.clone(), rhs.clone()), (rhs.clone(), lhs.clone())] {
+        let rule = Rule {loc: loc.clone(), doc: vec![], head, body, condition: None};
+        let mut named_rules: Vec<(&str, &Rule)> = rules.iter().map(|(name, rule)| (name.as_str(), rule)).collect();
+        named_rules.push(("", &rule));
+        if termination::check(order, &named_rules).is_ok() {
+            return Ok(rule)
+        }
+    }
+    Err(Failure::Unorientable(lhs, rhs))
+}
+
+/// Renames the variables to `A`, `B`, `C`, ... in the order of their first occurrence
+fn canonical_vars(rule: &mut Rule) {
+    let names = ('A'..='Z').map(String::from)
+        .chain((1..).flat_map(|i| ('A'..='Z').map(move |x| format!("{}{}", x, i))));
+    let renaming: Bindings = rule.head.vars().into_iter().chain(rule.body.vars())
+        .fold(Vec::new(), |mut vars, var| {
+            if !vars.contains(&var) {
+                vars.push(var);
+            }
+            vars
+        })
+        .into_iter()
+        .zip(names.map(Expr::Var))
+        .collect();
+    rule.head = substitute_bindings(&renaming, &rule.head);
+    rule.body = substitute_bindings(&renaming, &rule.body);
+}
+
+fn complete_impl(order: Order, rules: &mut Vec<(String, Rule)>, mut equations: Vec<(String, Expr, Expr)>, step_limit: usize, loc: &Loc) -> Result<(), Failure> {
+    // Completion is purely equational, evaluating primitives would be a rewrite it does not know about
+    let primitives = Primitives::none();
+    let mut fresh_names = (1..).map(|i| format!("cp{}", i));
+    for _ in 0..step_limit {
+        // Smaller equations first, they tend to make the bigger ones joinable
+        let Some(smallest) = (0..equations.len()).min_by_key(|&i| size(&equations[i].1) + size(&equations[i].2)) else {
+            return Ok(())
+        };
+        let (name, lhs, rhs) = equations.remove(smallest);
+        let lhs = normalize(&lhs, rules, &primitives)?;
+        let rhs = normalize(&rhs, rules, &primitives)?;
+        if lhs.fold_numerals() == rhs.fold_numerals() {
+            continue
+        }
+        let rule = orient(order, rules, lhs, rhs, loc)?;
+
+        // Rules whose heads the new rule rewrites go back to being equations
+        let mut kept_rules = Vec::new();
+        for (old_name, old_rule) in rules.drain(..) {
+            if rule.match_positions(&old_rule.head, &Theories::default()).is_empty() {
+                kept_rules.push((old_name, old_rule));
+            } else {
+                equations.push((old_name, old_rule.head, old_rule.body));
+            }
+        }
+        *rules = kept_rules;
+        rules.push((name.clone(), rule));
+        for i in 0..rules.len() {
+            rules[i].1.body = normalize(&rules[i].1.body, rules, &primitives)?;
+        }
+
+        let named_rules: Vec<(&str, &Rule)> = rules.iter().map(|(name, rule)| (name.as_str(), rule)).collect();
+        for pair in critical_pairs(&named_rules) {
+            if pair.outer != name && pair.inner != name {
+                continue
+            }
+            let pair_name = fresh_names.by_ref()
+                .find(|fresh| rules.iter().all(|(name, _)| name != fresh) && equations.iter().all(|(name, _, _)| name != fresh))
+                .unwrap();
+            equations.push((pair_name, pair.left, pair.right));
+        }
+    }
+    if equations.is_empty() { Ok(()) } else { Err(Failure::StepLimitExceeded) }
+}
+
+/// Completes the named `equations` into rules that terminate under `order`,
+/// processing at most `step_limit` equations. The rules produced before a
+/// failure are still returned.
+pub fn complete(order: Order, equations: Vec<(String, Expr, Expr)>, step_limit: usize, loc: &Loc) -> Completion {
+    let mut rules = Vec::new();
+    let failure = complete_impl(order, &mut rules, equations, step_limit, loc).err();
+    for (_, rule) in &mut rules {
+        canonical_vars(rule);
+    }
+    Completion {rules, failure}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::expr;
+    use crate::DisplayWithNotation;
+
+    fn equation(name: &str, source: &str) -> (String, Expr, Expr) {
+        let (lhs, rhs) = source.split_once(" = ").unwrap();
+        (name.to_string(), expr(lhs), expr(rhs))
+    }
+
+    fn rules(completion: &Completion) -> Vec<String> {
+        let notation = crate::Context::default().notation;
+        completion.rules.iter().map(|(name, rule)| format!("{} {}", name, rule.with(&notation))).collect()
+    }
+
+    #[test]
+    pub fn adds_critical_pairs() {
+        let loc = Loc { file_path: None, row: 1, col: 1 };
+        let completion = complete(Order::Lpo, vec![equation("ff", "f(f(X)) = g(X)")], 100, &loc);
+        assert_eq!(completion.failure, None);
+        assert_eq!(rules(&completion), ["ff f(f(A)) = g(A)", "cp1 f(g(A)) = g(f(A))"]);
+    }
+
+    #[test]
+    pub fn groups() {
+        let loc = Loc { file_path: None, row: 1, col: 1 };
+        let equations = vec![
+            equation("unit", "e * X = X"),
+            equation("inverse", "i(X) * X = e"),
+            equation("assoc", "(X * Y) * Z = X * (Y * Z)"),
+        ];
+        let completion = complete(Order::Lpo, equations, 1000, &loc);
+        assert_eq!(completion.failure, None);
+        assert_eq!(completion.rules.len(), 10);
+        assert!(rules(&completion).iter().any(|rule| rule.ends_with(" i(A * B) = i(B) * i(A)")));
+
+        let (_, lhs, rhs) = equation("comm", "X * Y = Y * X");
+        let completion = complete(Order::Lpo, vec![("comm".to_string(), lhs.clone(), rhs.clone())], 1000, &loc);
+        assert_eq!(completion.failure, Some(Failure::Unorientable(lhs, rhs)));
+    }
+}
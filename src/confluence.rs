@@ -0,0 +1,157 @@
+//! Local confluence checking. Two rules whose heads overlap can rewrite the
+//! same expression in two different ways, the results form a critical pair.
+//! A terminating set of rules is confluent if every critical pair rewrites to
+//! a common normal form.
+
+use std::io::Write;
+
+use super::{Bindings, DisplayWithNotation, Expr, Notation, Path, Rule, RuleSet, Strategy, DEFAULT_STEP_LIMIT, substitute_bindings};
+use super::unify::unify;
+
+/// The positions of all the subterms of `expr` that are not variables
+fn non_variable_positions(expr: &Expr) -> Vec<Vec<usize>> {
+    fn non_variable_positions_impl(expr: &Expr, path: &mut Vec<usize>, positions: &mut Vec<Vec<usize>>) {
+        if matches!(expr, Expr::Var(_)) {
+            return
+        }
+        positions.push(path.clone());
+        if let Expr::Fun(_, args) = expr {
+            for (i, arg) in args.iter().enumerate() {
+                path.push(i);
+                non_variable_positions_impl(arg, path, positions);
+                path.pop();
+            }
+        }
+    }
+
+    let mut positions = Vec::new();
+    non_variable_positions_impl(expr, &mut vec![], &mut positions);
+    positions
+}
+
+/// Renames the variables of `rule` with numeric suffixes so they don't clash with `taken`
+fn rename_apart(rule: &Rule, taken: &[String]) -> (Expr, Expr) {
+    let mut renaming = Bindings::new();
+    for var in rule.head.vars().into_iter().chain(rule.body.vars()) {
+        let fresh = (1..).map(|i| format!("{}{}", var, i)).find(|name| !taken.contains(name)).unwrap();
+        renaming.insert(var, Expr::Var(fresh));
+    }
+    (substitute_bindings(&renaming, &rule.head), substitute_bindings(&renaming, &rule.body))
+}
+
+/// The `outer` rule rewrites `peak` at its root into `left`, the `inner` one
+/// rewrites it at `path` into `right`.
+pub struct CriticalPair<'a> {
+    pub outer: &'a str,
+    pub inner: &'a str,
+    pub path: Path,
+    pub peak: Expr,
+    pub left: Expr,
+    pub right: Expr,
+}
+
+/// All the critical pairs between the given rules. Conditional rules are
+/// skipped, whether they overlap depends on their conditions.
+pub fn critical_pairs<'a>(rules: &[(&'a str, &'a Rule)]) -> Vec<CriticalPair<'a>> {
+    let rules: Vec<&(&str, &Rule)> = rules.iter().filter(|(_, rule)| rule.condition.is_none()).collect();
+    let mut pairs = Vec::new();
+    for (outer_name, outer) in &rules {
+        let outer_vars: Vec<String> = outer.head.vars().into_iter().chain(outer.body.vars()).collect();
+        for (inner_name, inner) in &rules {
+            let (inner_head, inner_body) = rename_apart(inner, &outer_vars);
+            for path in non_variable_positions(&outer.head) {
+                // A rule trivially overlaps with itself at the root
+                if path.is_empty() && outer_name == inner_name {
+                    continue
+                }
+                let subterm = outer.head.subterm(&path).expect("Positions come from the head itself");
+                let Ok(unifier) = unify(subterm, &inner_head) else {
+                    continue
+                };
+                let peak = unifier.apply(&outer.head);
+                let right = peak.replace_subterm(&path, unifier.apply(&inner_body))
+                    .expect("Positions come from the head itself");
+                pairs.push(CriticalPair {
+                    outer: outer_name,
+                    inner: inner_name,
+                    path: Path(path),
+                    peak,
+                    left: unifier.apply(&outer.body),
+                    right,
+                });
+            }
+        }
+    }
+    pairs
+}
+
+/// Leftmost-outermost rewrites of `start`, each with the name of the rule that did it
+pub struct Derivation<'a> {
+    pub start: Expr,
+    pub steps: Vec<(&'a str, Expr)>,
+    /// Whether the last expression is a normal form, or the step limit was hit before that
+    pub normalized: bool,
+}
+
+impl<'a> Derivation<'a> {
+    /// Fails with the normal form of a condition that is neither `true` nor `false`
+    pub fn normalize(start: &Expr, rules: &RuleSet<'a>) -> Result<Self, Expr> {
+        let mut derivation = Self {
+            start: start.clone(),
+            steps: Vec::new(),
+            normalized: false,
+        };
+        let mut current = start.clone();
+        for _ in 0..DEFAULT_STEP_LIMIT {
+            let mut fired = "";
+            let next = Strategy::outermost_step(&current, &mut |expr| {
+                Ok(rules.rewrite_named(expr)?.map(|(name, new_expr)| {
+                    fired = name;
+                    new_expr
+                }))
+            })?;
+            let Some(next) = next else {
+                derivation.normalized = true;
+                break
+            };
+            derivation.steps.push((fired, next.clone()));
+            current = next;
+        }
+        Ok(derivation)
+    }
+
+    pub fn end(&self) -> &Expr {
+        self.steps.last().map(|(_, expr)| expr).unwrap_or(&self.start)
+    }
+
+    pub fn print(&self, notation: &Notation, output: &mut dyn Write) {
+        outputln!(output, "    {}", self.start.with(notation));
+        for (name, expr) in &self.steps {
+            outputln!(output, "      => {} ({})", expr.with(notation), name);
+        }
+        if !self.normalized {
+            outputln!(output, "      => ... (no normal form within {} steps)", DEFAULT_STEP_LIMIT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{expr, rule};
+
+    #[test]
+    pub fn overlapping_rules() {
+        let inverse = rule("f(g(X))", "X");
+        let twice = rule("g(g(X))", "X");
+        let rules = [("inverse", &inverse), ("twice", &twice)];
+        let pairs = critical_pairs(&rules);
+        assert_eq!(pairs.len(), 2);
+        // f(g(g(X1))) is both g(X1) and f(X1)
+        assert_eq!((pairs[0].outer, pairs[0].inner, &pairs[0].path), ("inverse", "twice", &Path(vec![0])));
+        assert_eq!((&pairs[0].left, &pairs[0].right), (&expr("g(X1)"), &expr("f(X1)")));
+        // g(g(g(X1))) rewrites to g(X1) either way, the overlap of `twice` with itself
+        assert_eq!((pairs[1].outer, pairs[1].inner, &pairs[1].path), ("twice", "twice", &Path(vec![0])));
+        assert_eq!((&pairs[1].left, &pairs[1].right), (&expr("g(X1)"), &expr("g(X1)")));
+    }
+}
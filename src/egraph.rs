@@ -0,0 +1,513 @@
+//! Equality saturation. Instead of replacing a subterm, applying a rule to an
+//! e-graph records that both sides are equal, so every expression reachable
+//! by the rules stays around. The best one is extracted at the end by a cost
+//! function.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::{pattern_match, substitute_bindings, BigInt, Expr, Rule};
+use super::primitives::Primitives;
+use super::theories::Theories;
+
+/// Index of an e-class, a set of expressions known to be equal
+pub type Id = usize;
+
+/// An expression whose arguments are e-classes. Symbols, variables and numbers
+/// are leaves. Variables of the simplified expression are just opaque leaves,
+/// only the variables of rules match anything.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Node {
+    Leaf(Expr),
+    Fun(String, Vec<Id>),
+}
+
+#[derive(Default)]
+pub struct EGraph {
+    /// Union-find of the e-classes, `find` follows it to the canonical ones
+    parents: Vec<Id>,
+    /// Nodes of the canonical e-classes. Ordered so that extraction breaks ties the same way every time.
+    classes: BTreeMap<Id, Vec<Node>>,
+    /// Canonical e-class of every node
+    memo: HashMap<Node, Id>,
+}
+
+fn find(parents: &[Id], mut id: Id) -> Id {
+    while parents[id] != id {
+        id = parents[id];
+    }
+    id
+}
+
+/// The constant that a term over constants rewrites to by the `rules`, the
+/// primitives and the Peano numerals, innermost first and within `steps`
+/// rewrites. Unfolding a recursive rule nests as deep as the number it
+/// recurses on, so the stack of the unfinished applications is explicit.
+fn evaluate(expr: &Expr, rules: &[&Rule], primitives: &Primitives, theories: &Theories, mut steps: usize) -> Option<Expr> {
+    let reduce = |term: &Expr| {
+        if let Expr::Fun(name, args) = term {
+            if let ("s", [Expr::Num(n)]) = (name.as_str(), args.as_slice()) {
+                if !n.is_negative() {
+                    return Some(Expr::Num(n + &BigInt::from(1)))
+                }
+            }
+        }
+        primitives.eval(term).or_else(|| rules.iter().find_map(|rule| {
+            pattern_match(&rule.head, term, theories).map(|bindings| substitute_bindings(&bindings, &rule.body))
+        }))
+    };
+    // Applications with their evaluated arguments and the ones still to go, in reverse
+    let mut stack: Vec<(String, Vec<Expr>, Vec<Expr>)> = Vec::new();
+    let mut term = expr.clone();
+    loop {
+        while let Expr::Fun(name, args) = &mut term {
+            let mut rest = std::mem::take(args);
+            rest.reverse();
+            let Some(first) = rest.pop() else { break };
+            stack.push((std::mem::take(name), Vec::new(), rest));
+            term = first;
+        }
+        loop {
+            if let Some(next) = reduce(&term) {
+                steps = steps.checked_sub(1)?;
+                term = next;
+                break
+            }
+            if !matches!(term, Expr::Num(_) | Expr::Sym(_)) {
+                return None
+            }
+            let Some((name, mut done, mut rest)) = stack.pop() else {
+                return Some(term)
+            };
+            done.push(term);
+            match rest.pop() {
+                Some(next) => {
+                    stack.push((name, done, rest));
+                    term = next;
+                    break
+                }
+                None => term = Expr::Fun(name, done),
+            }
+        }
+    }
+}
+
+fn canonical(parents: &[Id], node: &Node) -> Node {
+    match node {
+        Node::Leaf(_) => node.clone(),
+        Node::Fun(name, args) => Node::Fun(name.clone(), args.iter().map(|arg| find(parents, *arg)).collect()),
+    }
+}
+
+/// Why [`EGraph::saturate`] stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    /// The rules can't add anything new anymore, after the given amount of iterations
+    Saturated(usize),
+    NodeLimit,
+    TimeLimit,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub nodes: usize,
+    pub time: Duration,
+}
+
+pub const DEFAULT_NODE_LIMIT: usize = 10000;
+pub const DEFAULT_TIME_LIMIT_MS: u64 = 1000;
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            nodes: DEFAULT_NODE_LIMIT,
+            time: Duration::from_millis(DEFAULT_TIME_LIMIT_MS),
+        }
+    }
+}
+
+impl EGraph {
+    pub fn find(&self, id: Id) -> Id {
+        find(&self.parents, id)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.memo.len()
+    }
+
+    fn add(&mut self, node: Node) -> Id {
+        let node = canonical(&self.parents, &node);
+        if let Some(id) = self.memo.get(&node) {
+            return self.find(*id)
+        }
+        let id = self.parents.len();
+        self.parents.push(id);
+        self.classes.insert(id, vec![node.clone()]);
+        self.memo.insert(node, id);
+        id
+    }
+
+    pub fn add_expr(&mut self, expr: &Expr) -> Id {
+        self.add_instance(expr, &HashMap::new())
+    }
+
+    /// Adds `expr` with its variables standing for the bound e-classes
+    fn add_instance(&mut self, expr: &Expr, bindings: &HashMap<String, Id>) -> Id {
+        match expr {
+            Expr::Var(name) if bindings.contains_key(name) => self.find(bindings[name]),
+            Expr::Sym(_) | Expr::Var(_) | Expr::Num(_) => self.add(Node::Leaf(expr.clone())),
+            Expr::Fun(name, args) => {
+                let args = args.iter().map(|arg| self.add_instance(arg, bindings)).collect();
+                self.add(Node::Fun(name.clone(), args))
+            }
+        }
+    }
+
+    /// Merges the e-classes, returns whether they were different
+    fn union(&mut self, a: Id, b: Id) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false
+        }
+        let (a, b) = if self.classes[&a].len() >= self.classes[&b].len() { (a, b) } else { (b, a) };
+        self.parents[b] = a;
+        let nodes = self.classes.remove(&b).expect("Canonical e-classes always have nodes");
+        self.classes.get_mut(&a).expect("Canonical e-classes always have nodes").extend(nodes);
+        true
+    }
+
+    /// Restores congruence after unions: nodes that became equal because
+    /// their arguments were merged put their e-classes together too.
+    fn rebuild(&mut self) {
+        loop {
+            let mut memo = HashMap::new();
+            let mut merges = Vec::new();
+            for (id, nodes) in self.classes.iter_mut() {
+                let mut canonical_nodes: Vec<Node> = Vec::new();
+                for node in nodes.iter() {
+                    let node = canonical(&self.parents, node);
+                    if !canonical_nodes.contains(&node) {
+                        canonical_nodes.push(node);
+                    }
+                }
+                for node in &canonical_nodes {
+                    if let Some(other) = memo.insert(node.clone(), *id) {
+                        merges.push((other, *id));
+                    }
+                }
+                *nodes = canonical_nodes;
+            }
+            self.memo = memo;
+            if merges.is_empty() {
+                break
+            }
+            for (a, b) in merges {
+                self.union(a, b);
+            }
+        }
+    }
+
+    /// All the ways `pattern` matches some expression of the e-class `id`.
+    /// Numbers match `s(P)` like Peano numerals, through the e-class of their
+    /// predecessor. The predecessors that are not in the e-graph yet are
+    /// pushed to `missing` instead.
+    fn ematch(&self, pattern: &Expr, id: Id, bindings: HashMap<String, Id>, missing: &mut Vec<BigInt>) -> Vec<HashMap<String, Id>> {
+        let id = self.find(id);
+        match pattern {
+            Expr::Var(name) => match bindings.get(name) {
+                Some(bound) if self.find(*bound) != id => vec![],
+                Some(_) => vec![bindings],
+                None => {
+                    let mut bindings = bindings;
+                    bindings.insert(name.clone(), id);
+                    vec![bindings]
+                }
+            }
+            Expr::Sym(_) | Expr::Num(_) => {
+                if self.classes[&id].contains(&Node::Leaf(pattern.clone())) { vec![bindings] } else { vec![] }
+            }
+            Expr::Fun(name, pattern_args) => {
+                let mut matches = Vec::new();
+                for node in &self.classes[&id] {
+                    let args = match node {
+                        Node::Fun(node_name, args) if node_name == name && args.len() == pattern_args.len() => args.clone(),
+                        Node::Leaf(Expr::Num(n)) if name == "s" && pattern_args.len() == 1 && *n > BigInt::zero() => {
+                            let predecessor = n - &BigInt::from(1);
+                            match self.memo.get(&Node::Leaf(Expr::Num(predecessor.clone()))) {
+                                Some(arg) => vec![*arg],
+                                None => {
+                                    missing.push(predecessor);
+                                    continue
+                                }
+                            }
+                        }
+                        _ => continue,
+                    };
+                    let mut partial = vec![bindings.clone()];
+                    for (pattern_arg, arg) in pattern_args.iter().zip(&args) {
+                        partial = partial.into_iter().flat_map(|bindings| self.ematch(pattern_arg, *arg, bindings, missing)).collect();
+                    }
+                    matches.extend(partial);
+                }
+                matches
+            }
+        }
+    }
+
+    fn number(&self, id: Id) -> Option<&BigInt> {
+        self.classes[&self.find(id)].iter().find_map(|node| match node {
+            Node::Leaf(Expr::Num(n)) => Some(n),
+            _ => None,
+        })
+    }
+
+    /// Applies the `rules` and evaluates the `primitives` and the Peano numerals everywhere until
+    /// nothing changes or the `limits` are hit. Applications to numbers are evaluated
+    /// by the rules directly, rather than unfolded one rewrite per iteration. Conditional rules are not
+    /// supported, since their conditions are about single expressions.
+    ///
+    /// Matching modulo the `theories` comes from their laws, applied like the
+    /// rules to binary applications: the expressions of the e-graph have to be
+    /// [nested](Theories::nest), and so are the rules. Commutativity is only
+    /// known for binary symbols.
+    pub fn saturate(&mut self, rules: &[&Rule], primitives: &Primitives, theories: &Theories, limits: Limits) -> Stop {
+        let rules: Vec<&Rule> = rules.iter().copied().filter(|rule| rule.condition.is_none()).collect();
+        let mut rewrites: Vec<(Expr, Expr)> = rules.iter()
+            .map(|rule| (theories.nest(&rule.head), theories.nest(&rule.body)))
+            .collect();
+        for (name, theory) in theories.iter() {
+            let apply = |a: &Expr, b: &Expr| Expr::Fun(name.to_string(), vec![a.clone(), b.clone()]);
+            let [x, y, z] = ["X", "Y", "Z"].map(|var| Expr::Var(var.to_string()));
+            if theory.comm {
+                rewrites.push((apply(&x, &y), apply(&y, &x)));
+            }
+            if theory.assoc {
+                rewrites.push((apply(&apply(&x, &y), &z), apply(&x, &apply(&y, &z))));
+                rewrites.push((apply(&x, &apply(&y, &z)), apply(&apply(&x, &y), &z)));
+            }
+        }
+
+        // Applications to numbers that were evaluated already, as far as they go
+        let mut tried = HashSet::new();
+        // E-classes that have a constant as the value of one of them
+        let mut evaluated_classes = Vec::new();
+        let start = Instant::now();
+        for iteration in 0.. {
+            // Evaluated first, so that the rules don't unfold where the number is known already
+            let mut evaluated = Vec::new();
+            for (id, nodes) in &self.classes {
+                for node in nodes {
+                    let Node::Fun(name, args) = node else { continue };
+                    if tried.contains(node) {
+                        continue
+                    }
+                    let Some(args) = args.iter().map(|arg| self.number(*arg).cloned()).collect::<Option<Vec<_>>>() else { continue };
+                    tried.insert(node.clone());
+                    // Peano numerals are the numbers they denote, like `fold_numerals` has it. Every
+                    // step would have been at least one more node of the e-graph when rewriting it there.
+                    let term = Expr::Fun(name.clone(), args.into_iter().map(Expr::Num).collect());
+                    evaluated.extend(evaluate(&term, &rules, primitives, theories, limits.nodes).map(|value| (*id, value)));
+                }
+            }
+            let mut changed = false;
+            for (id, value) in evaluated {
+                let new_id = self.add_expr(&value);
+                changed |= self.union(id, new_id);
+                evaluated_classes.push(id);
+            }
+            self.rebuild();
+            // The value is as simple as it gets already
+            let constants: HashSet<Id> = evaluated_classes.iter().map(|id| self.find(*id)).collect();
+
+            let mut matches = Vec::new();
+            let mut missing = Vec::new();
+            for (head, body) in &rewrites {
+                for id in self.classes.keys().filter(|id| !constants.contains(id)) {
+                    for bindings in self.ematch(head, *id, HashMap::new(), &mut missing) {
+                        matches.push((*id, body, bindings));
+                    }
+                }
+            }
+            for (id, body, bindings) in matches {
+                let new_id = self.add_instance(body, &bindings);
+                changed |= self.union(id, new_id);
+                if self.node_count() > limits.nodes {
+                    self.rebuild();
+                    return Stop::NodeLimit
+                }
+            }
+            // The next iteration matches through them
+            for predecessor in missing {
+                let nodes = self.node_count();
+                self.add(Node::Leaf(Expr::Num(predecessor)));
+                changed |= self.node_count() > nodes;
+            }
+            self.rebuild();
+            if !changed {
+                return Stop::Saturated(iteration)
+            }
+            if start.elapsed() > limits.time {
+                return Stop::TimeLimit
+            }
+        }
+        unreachable!()
+    }
+
+    /// The cheapest expression of the e-class `id` according to `cost`
+    pub fn extract(&self, id: Id, cost: &Cost) -> Expr {
+        let mut best: HashMap<Id, (usize, &Node)> = HashMap::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (id, nodes) in &self.classes {
+                for node in nodes {
+                    let node_cost = match node {
+                        Node::Leaf(expr) => cost.leaf(expr),
+                        Node::Fun(name, args) => {
+                            let Some(arg_costs) = args.iter().map(|arg| best.get(&self.find(*arg)).map(|(cost, _)| *cost)).collect::<Option<Vec<_>>>() else {
+                                continue
+                            };
+                            cost.fun(name, &arg_costs)
+                        }
+                    };
+                    if best.get(id).is_none_or(|(best_cost, _)| node_cost < *best_cost) {
+                        best.insert(*id, (node_cost, node));
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        fn build(egraph: &EGraph, best: &HashMap<Id, (usize, &Node)>, id: Id) -> Expr {
+            match best[&egraph.find(id)].1 {
+                Node::Leaf(expr) => expr.clone(),
+                Node::Fun(name, args) => Expr::Fun(name.clone(), args.iter().map(|arg| build(egraph, best, *arg)).collect()),
+            }
+        }
+        build(self, &best, id)
+    }
+}
+
+/// What makes an expression better than another equal one, lower is better
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cost {
+    /// Amount of symbols
+    Size,
+    /// Longest path from the root to a leaf
+    Depth,
+    /// Sum of the weights of the symbols, the ones that are not listed weigh 1
+    Weights(Vec<(String, usize)>),
+}
+
+impl Cost {
+    fn weight(&self, name: &str) -> usize {
+        match self {
+            Cost::Weights(weights) => weights.iter().find(|(symbol, _)| symbol == name).map(|(_, weight)| *weight).unwrap_or(1),
+            Cost::Size | Cost::Depth => 1,
+        }
+    }
+
+    fn leaf(&self, expr: &Expr) -> usize {
+        match expr {
+            Expr::Sym(name) => self.weight(name),
+            _ => 1,
+        }
+    }
+
+    fn fun(&self, name: &str, arg_costs: &[usize]) -> usize {
+        match self {
+            Cost::Size => 1 + arg_costs.iter().sum::<usize>(),
+            Cost::Depth => 1 + arg_costs.iter().max().unwrap_or(&0),
+            Cost::Weights(_) => self.weight(name).saturating_add(arg_costs.iter().sum::<usize>()),
+        }
+    }
+}
+
+impl fmt::Display for Cost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cost::Size => write!(f, "size"),
+            Cost::Depth => write!(f, "depth"),
+            Cost::Weights(weights) => {
+                write!(f, "weights")?;
+                for (i, (name, weight)) in weights.iter().enumerate() {
+                    write!(f, "{} {}: {}", if i > 0 { "," } else { "" }, name, weight)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{expr, rule};
+
+    fn simplify(rules: &[Rule], start: &str, cost: &Cost) -> (Stop, Expr) {
+        let mut egraph = EGraph::default();
+        let root = egraph.add_expr(&expr(start));
+        let rules: Vec<&Rule> = rules.iter().collect();
+        let stop = egraph.saturate(&rules, &Primitives::default(), &Theories::default(), Limits { nodes: 1000, ..Limits::default() });
+        (stop, egraph.extract(root, cost))
+    }
+
+    #[test]
+    pub fn keeps_alternatives() {
+        // Rewriting with `shift` first would lose the way to cancel the division
+        let rules = [
+            rule("mul(X, 2)", "shift(X)"),
+            rule("over(mul(X, Y), Z)", "mul(X, over(Y, Z))"),
+            rule("over(X, X)", "1"),
+            rule("mul(X, 1)", "X"),
+        ];
+        let (stop, simplified) = simplify(&rules, "over(mul(a, 2), 2)", &Cost::Size);
+        assert!(matches!(stop, Stop::Saturated(_)));
+        assert_eq!(simplified, expr("a"));
+        assert_eq!(simplify(&rules, "mul(3, 1 + 1)", &Cost::Size).1, expr("shift(3)"));
+    }
+
+    #[test]
+    pub fn cost_functions() {
+        let rules = [
+            rule("add(add(X, Y), Z)", "add(X, add(Y, Z))"),
+            rule("add(X, add(Y, Z))", "add(add(X, Y), Z)"),
+        ];
+        let (stop, balanced) = simplify(&rules, "add(add(add(a, b), c), d)", &Cost::Depth);
+        assert!(matches!(stop, Stop::Saturated(_)));
+        assert_eq!(balanced, expr("add(add(a, b), add(c, d))"));
+
+        let rules = [rule("f(X)", "g(g(X))")];
+        assert_eq!(simplify(&rules, "f(a)", &Cost::Size).1, expr("f(a)"));
+        assert_eq!(simplify(&rules, "f(a)", &Cost::Weights(vec![("f".to_string(), 5)])).1, expr("g(g(a))"));
+
+        // Sums under associativity and commutativity have exponentially many forms
+        let rules = [rule("add(X, Y)", "add(Y, X)"), rule("add(add(X, Y), Z)", "add(X, add(Y, Z))")];
+        let sum = "add(add(add(add(add(add(a, b), c), d), e), f), g)";
+        assert_eq!(simplify(&rules, sum, &Cost::Size).0, Stop::NodeLimit);
+    }
+
+    #[test]
+    pub fn numbers_match_peano_numerals() {
+        let rules = [rule("add(0, Y)", "Y"), rule("add(s(X), Y)", "s(add(X, Y))")];
+        let rules: Vec<&Rule> = rules.iter().collect();
+        let mut egraph = EGraph::default();
+        let root = egraph.add_expr(&expr("add(2, y)"));
+        let stop = egraph.saturate(&rules, &Primitives::none(), &Theories::default(), Limits::default());
+        assert!(matches!(stop, Stop::Saturated(_)));
+        let numeral = egraph.add_expr(&expr("s(s(y))"));
+        assert_eq!(egraph.find(root), egraph.find(numeral));
+    }
+
+    #[test]
+    pub fn evaluates_numbers_directly() {
+        let rules = [rule("add(0, Y)", "Y"), rule("add(s(X), Y)", "s(add(X, Y))")];
+        let rules: Vec<&Rule> = rules.iter().collect();
+        let mut egraph = EGraph::default();
+        let root = egraph.add_expr(&expr("add(5000, 1)"));
+        let stop = egraph.saturate(&rules, &Primitives::none(), &Theories::default(), Limits::default());
+        assert!(matches!(stop, Stop::Saturated(_)));
+        assert_eq!(egraph.extract(root, &Cost::Size), expr("5001"));
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::fmt;
 use std::iter::Peekable;
 
@@ -30,23 +31,55 @@ macro_rules! token_kind_enum {
 
 token_kind_enum! {
     Sym,
+    Number,
+    Str,
 
     // Keywords
     Rule,
     Shape,
     Apply,
     Done,
+    Undo,
+    Redo,
+    History,
     Quit,
+    Show,
+    Infix,
+    Infixl,
+    Infixr,
+    Set,
+    Eval,
+    Check,
+    Unify,
+    Complete,
+    Simplify,
+    Assoc,
+    Comm,
+    Ac,
+    Sig,
+    Include,
+    Module,
+    Import,
+    Load,
+
+    // Comments
+    DocComment,
 
     // Special Characters
     OpenParen,
     CloseParen,
+    OpenBrace,
+    CloseBrace,
     Comma,
     Equals,
     Colon,
+    Dot,
+    Op,
 
     // Terminators
     Invalid,
+    UnclosedComment,
+    UnclosedString,
     End,
 }
 
@@ -82,8 +115,8 @@ impl fmt::Display for TokenKindSet {
             1 => write!(f, "{}", xs[0]),
             n => {
                 write!(f, "{}", xs[0])?;
-                for i in 1..n-1 {
-                    write!(f, ", {}", xs[i])?
+                for x in &xs[1..n-1] {
+                    write!(f, ", {}", x)?
                 }
                 write!(f, ", or {}", xs[n-1])
             }
@@ -94,6 +127,20 @@ impl fmt::Display for TokenKindSet {
 #[allow(dead_code)]
 const TOKEN_KIND_SIZE_ASSERT: [(); (TOKEN_KIND_ITEMS.len() < TokenKindSetInnerType::BITS as usize) as usize] = [()];
 
+/// Operators are the runs of these characters, except for a lone `=`. A run
+/// ends where a comment starts.
+pub fn is_operator_char(x: char) -> bool {
+    "+-*/<>!&|^%~=".contains(x)
+}
+
+fn operator_token(text: String, loc: Loc) -> Token {
+    if text == "=" {
+        Token {kind: TokenKind::Equals, text, loc}
+    } else {
+        Token {kind: TokenKind::Op, text, loc}
+    }
+}
+
 fn keyword_by_name(text: &str) -> Option<TokenKind> {
     match text {
         "rule"  => Some(TokenKind::Rule),
@@ -101,6 +148,35 @@ fn keyword_by_name(text: &str) -> Option<TokenKind> {
         "apply" => Some(TokenKind::Apply),
         "done"  => Some(TokenKind::Done),
         "quit"  => Some(TokenKind::Quit),
+        "infix" => Some(TokenKind::Infix),
+        "infixl" => Some(TokenKind::Infixl),
+        "infixr" => Some(TokenKind::Infixr),
+        _ => None,
+    }
+}
+
+/// The command that a symbol starts. Command words are lexed as symbols, so
+/// they stay free to use as names everywhere else.
+pub fn command_by_name(text: &str) -> Option<TokenKind> {
+    match text {
+        "undo"  => Some(TokenKind::Undo),
+        "redo"  => Some(TokenKind::Redo),
+        "history" => Some(TokenKind::History),
+        "show"  => Some(TokenKind::Show),
+        "set"   => Some(TokenKind::Set),
+        "eval"  => Some(TokenKind::Eval),
+        "check" => Some(TokenKind::Check),
+        "unify" => Some(TokenKind::Unify),
+        "complete" => Some(TokenKind::Complete),
+        "simplify" => Some(TokenKind::Simplify),
+        "assoc" => Some(TokenKind::Assoc),
+        "comm"  => Some(TokenKind::Comm),
+        "ac"    => Some(TokenKind::Ac),
+        "sig"   => Some(TokenKind::Sig),
+        "include" => Some(TokenKind::Include),
+        "module" => Some(TokenKind::Module),
+        "import" => Some(TokenKind::Import),
+        "load"  => Some(TokenKind::Load),
         _ => None,
     }
 }
@@ -110,17 +186,47 @@ impl fmt::Display for TokenKind {
         use TokenKind::*;
         match self {
             Sym => write!(f, "symbol"),
+            Number => write!(f, "number"),
+            Str => write!(f, "string"),
             Rule => write!(f, "`rule`"),
             Shape => write!(f, "`shape`"),
             Apply => write!(f, "`apply`"),
             Done => write!(f, "`done`"),
+            Undo => write!(f, "`undo`"),
+            Redo => write!(f, "`redo`"),
+            History => write!(f, "`history`"),
             Quit => write!(f, "`quit`"),
+            Show => write!(f, "`show`"),
+            Infix => write!(f, "`infix`"),
+            Infixl => write!(f, "`infixl`"),
+            Infixr => write!(f, "`infixr`"),
+            Set => write!(f, "`set`"),
+            Eval => write!(f, "`eval`"),
+            Check => write!(f, "`check`"),
+            Unify => write!(f, "`unify`"),
+            Complete => write!(f, "`complete`"),
+            Simplify => write!(f, "`simplify`"),
+            Assoc => write!(f, "`assoc`"),
+            Comm => write!(f, "`comm`"),
+            Ac => write!(f, "`ac`"),
+            Sig => write!(f, "`sig`"),
+            Include => write!(f, "`include`"),
+            Module => write!(f, "`module`"),
+            Import => write!(f, "`import`"),
+            Load => write!(f, "`load`"),
+            DocComment => write!(f, "doc comment"),
             OpenParen => write!(f, "open paren"),
             CloseParen => write!(f, "close paren"),
+            OpenBrace => write!(f, "open brace"),
+            CloseBrace => write!(f, "close brace"),
             Comma => write!(f, "comma"),
             Equals => write!(f, "equals"),
             Colon => write!(f, "colon"),
+            Dot => write!(f, "dot"),
+            Op => write!(f, "operator"),
             Invalid => write!(f, "invalid token"),
+            UnclosedComment => write!(f, "unclosed block comment"),
+            UnclosedString => write!(f, "unclosed string"),
             End => write!(f, "end of input"),
         }
     }
@@ -133,9 +239,16 @@ pub struct Token {
     pub loc: Loc,
 }
 
+/// Whether a run of operator characters is a declared operator
+type IsOperator = Box<dyn Fn(&str) -> bool>;
+
 pub struct Lexer<Chars: Iterator<Item=char>> {
     chars: Peekable<Chars>,
+    /// Tokens that were lexed together with the previous one
+    pending: VecDeque<Token>,
     exhausted: bool,
+    /// Without it every run of operator characters is one operator
+    is_operator: Option<IsOperator>,
     file_path: Option<String>,
     lnum: usize,
     bol: usize,
@@ -143,10 +256,13 @@ pub struct Lexer<Chars: Iterator<Item=char>> {
 }
 
 impl<Chars: Iterator<Item=char>> Lexer<Chars> {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_iter(chars: Chars) -> Self {
         Self {
             chars: chars.peekable(),
+            pending: VecDeque::new(),
             exhausted: false,
+            is_operator: None,
             file_path: None,
             lnum: 0,
             bol: 0,
@@ -166,13 +282,19 @@ impl<Chars: Iterator<Item=char>> Lexer<Chars> {
         self.file_path = Some(file_path.to_string())
     }
 
+    /// A run that starts with `=` but is not a declared operator is lexed as
+    /// an equals followed by the rest of the run.
+    pub fn set_operators(&mut self, is_operator: impl Fn(&str) -> bool + 'static) {
+        self.is_operator = Some(Box::new(is_operator))
+    }
+
     fn drop_line(&mut self)
     {
-        while let Some(_) = self.chars.next_if(|x| *x != '\n')
+        while self.chars.next_if(|x| *x != '\n').is_some()
         {
             self.cnum += 1
         }
-        if let Some(_) = self.chars.next_if(|x| *x == '\n')
+        if self.chars.next_if(|x| *x == '\n').is_some()
         {
             self.cnum += 1;
             self.lnum += 1;
@@ -182,28 +304,90 @@ impl<Chars: Iterator<Item=char>> Lexer<Chars> {
 
     fn trim_whitespaces(&mut self)
     {
-        while let Some(_) = self.chars.next_if(|x| x.is_whitespace() && *x != '\n')
+        while self.chars.next_if(|x| x.is_whitespace() && *x != '\n').is_some()
         {
             self.cnum += 1
         }
     }
+
+    /// Drops the rest of a block comment whose opening `/*` was already
+    /// consumed. Block comments nest. Returns `false` if the input ends before
+    /// the comment is closed.
+    fn drop_block_comment(&mut self) -> bool
+    {
+        let mut depth = 1;
+        while depth > 0 {
+            let Some(x) = self.chars.next() else {
+                return false
+            };
+            self.cnum += 1;
+            match x {
+                '\n' => {
+                    self.lnum += 1;
+                    self.bol = self.cnum;
+                }
+                '*' if self.chars.next_if_eq(&'/').is_some() => {
+                    self.cnum += 1;
+                    depth -= 1;
+                }
+                '/' if self.chars.next_if_eq(&'*').is_some() => {
+                    self.cnum += 1;
+                    depth += 1;
+                }
+                _ => {}
+            }
+        }
+        true
+    }
+
+    /// Lexes a comment whose opening `/` was already consumed at `loc`, the
+    /// next character has to be a `/` or a `*`. Only doc comments and unclosed
+    /// block comments become tokens.
+    fn comment(&mut self, loc: Loc) -> Option<Token>
+    {
+        if self.chars.next_if_eq(&'/').is_some() {
+            self.cnum += 1;
+            let line = self.take_line();
+            // `///` starts a doc comment, but `////` is a regular comment again
+            let line = line.strip_prefix('/').filter(|line| !line.starts_with('/'))?;
+            let text = line.strip_prefix(' ').unwrap_or(line).to_string();
+            Some(Token {kind: TokenKind::DocComment, text, loc})
+        } else {
+            self.chars.next();
+            self.cnum += 1;
+            if self.drop_block_comment() {
+                None
+            } else {
+                self.exhausted = true;
+                Some(Token {kind: TokenKind::UnclosedComment, text: "/*".to_string(), loc})
+            }
+        }
+    }
+
+    /// Takes the rest of the line, without the newline itself
+    fn take_line(&mut self) -> String
+    {
+        let mut text = String::new();
+        while let Some(x) = self.chars.next_if(|x| *x != '\n')
+        {
+            self.cnum += 1;
+            text.push(x);
+        }
+        text
+    }
 }
 
 impl<Chars: Iterator<Item=char>> Iterator for Lexer<Chars> {
     type Item = Token;
 
     fn next(&mut self) -> Option<Token> {
+        if let Some(token) = self.pending.pop_front() { return Some(token) }
         if self.exhausted { return None }
 
         self.trim_whitespaces();
-        while let Some(x) = self.chars.peek() {
-            if *x != '\n' && *x != '#'
-            {
-                break
-            }
-
-            self.lnum += 1;
-            self.bol = self.cnum;
+        while let Some('\n' | '#') = self.chars.peek() {
+            self.drop_line();
+            self.trim_whitespaces();
         }
 
         let loc = self.loc();
@@ -214,9 +398,50 @@ impl<Chars: Iterator<Item=char>> Iterator for Lexer<Chars> {
                 match x {
                     '(' => Some(Token {kind: TokenKind::OpenParen,  text, loc}),
                     ')' => Some(Token {kind: TokenKind::CloseParen, text, loc}),
+                    '{' => Some(Token {kind: TokenKind::OpenBrace,  text, loc}),
+                    '}' => Some(Token {kind: TokenKind::CloseBrace, text, loc}),
                     ',' => Some(Token {kind: TokenKind::Comma,      text, loc}),
-                    '=' => Some(Token {kind: TokenKind::Equals,     text, loc}),
                     ':' => Some(Token {kind: TokenKind::Colon,      text, loc}),
+                    '.' => Some(Token {kind: TokenKind::Dot,        text, loc}),
+                    // Strings have no escapes and end at the end of the line
+                    '"' => {
+                        let mut value = String::new();
+                        while let Some(x) = self.chars.next_if(|x| *x != '"' && *x != '\n') {
+                            self.cnum += 1;
+                            value.push(x);
+                        }
+                        if self.chars.next_if_eq(&'"').is_some() {
+                            self.cnum += 1;
+                            Some(Token {kind: TokenKind::Str, text: value, loc})
+                        } else {
+                            self.exhausted = true;
+                            Some(Token {kind: TokenKind::UnclosedString, text, loc})
+                        }
+                    }
+                    '/' if matches!(self.chars.peek(), Some('/' | '*')) => {
+                        self.comment(loc).or_else(|| self.next())
+                    }
+                    _ if is_operator_char(x) => {
+                        let mut comment = None;
+                        while let Some(x) = self.chars.next_if(|x| is_operator_char(*x)) {
+                            let x_loc = self.loc();
+                            self.cnum += 1;
+                            if x == '/' && matches!(self.chars.peek(), Some('/' | '*')) {
+                                comment = self.comment(x_loc);
+                                break
+                            }
+                            text.push(x)
+                        }
+
+                        let declared = self.is_operator.as_ref().is_none_or(|is_operator| is_operator(&text));
+                        if text.len() > 1 && text.starts_with('=') && !declared {
+                            let rest = text.split_off(1);
+                            let rest_loc = Loc {col: loc.col + 1, ..loc.clone()};
+                            self.pending.push_back(operator_token(rest, rest_loc));
+                        }
+                        self.pending.extend(comment);
+                        Some(operator_token(text, loc))
+                    }
                     _ => {
                         if !x.is_alphanumeric() {
                             self.exhausted = true;
@@ -229,6 +454,8 @@ impl<Chars: Iterator<Item=char>> Iterator for Lexer<Chars> {
 
                             if let Some(kind) = keyword_by_name(&text) {
                                 Some(Token{kind, text, loc})
+                            } else if text.chars().all(|x| x.is_ascii_digit()) {
+                                Some(Token{kind: TokenKind::Number, text, loc})
                             } else {
                                 Some(Token{kind: TokenKind::Sym, text, loc})
                             }
@@ -245,3 +472,107 @@ impl<Chars: Iterator<Item=char>> Iterator for Lexer<Chars> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(source: &str) -> Vec<(TokenKind, String, usize, usize)> {
+        Lexer::from_iter(source.chars())
+            .map(|token| (token.kind, token.text, token.loc.row, token.loc.col))
+            .collect()
+    }
+
+    #[test]
+    pub fn comments() {
+        let source = "# line comment\n\n/// Adds zero\nrule // line comment\n/* block /* nested\n */ */ add0 /* */ x\n//// not a doc";
+        assert_eq!(lex(source), vec![
+            (TokenKind::DocComment, "Adds zero".to_string(), 3, 1),
+            (TokenKind::Rule, "rule".to_string(), 4, 1),
+            (TokenKind::Sym, "add0".to_string(), 6, 8),
+            (TokenKind::Sym, "x".to_string(), 6, 19),
+            (TokenKind::End, "".to_string(), 7, 15),
+        ]);
+    }
+
+    #[test]
+    pub fn operators() {
+        assert_eq!(lex("a+b = c<=-d / e"), vec![
+            (TokenKind::Sym, "a".to_string(), 1, 1),
+            (TokenKind::Op, "+".to_string(), 1, 2),
+            (TokenKind::Sym, "b".to_string(), 1, 3),
+            (TokenKind::Equals, "=".to_string(), 1, 5),
+            (TokenKind::Sym, "c".to_string(), 1, 7),
+            (TokenKind::Op, "<=-".to_string(), 1, 8),
+            (TokenKind::Sym, "d".to_string(), 1, 11),
+            (TokenKind::Op, "/".to_string(), 1, 13),
+            (TokenKind::Sym, "e".to_string(), 1, 15),
+            (TokenKind::End, "".to_string(), 1, 16),
+        ]);
+    }
+
+    #[test]
+    pub fn operators_end_before_comments() {
+        assert_eq!(lex("a +// trailing\nb -/// doc\nc */* block */d"), vec![
+            (TokenKind::Sym, "a".to_string(), 1, 1),
+            (TokenKind::Op, "+".to_string(), 1, 3),
+            (TokenKind::Sym, "b".to_string(), 2, 1),
+            (TokenKind::Op, "-".to_string(), 2, 3),
+            (TokenKind::DocComment, "doc".to_string(), 2, 4),
+            (TokenKind::Sym, "c".to_string(), 3, 1),
+            (TokenKind::Op, "*".to_string(), 3, 3),
+            (TokenKind::Sym, "d".to_string(), 3, 15),
+            (TokenKind::End, "".to_string(), 3, 16),
+        ]);
+    }
+
+    #[test]
+    pub fn equals_before_undeclared_operators() {
+        let mut lexer = Lexer::from_iter("f(X)=-X == Y =>".chars());
+        lexer.set_operators(|op| op == "==");
+        let tokens: Vec<(TokenKind, String, usize)> = lexer.map(|token| (token.kind, token.text, token.loc.col)).collect();
+        assert_eq!(tokens, vec![
+            (TokenKind::Sym, "f".to_string(), 1),
+            (TokenKind::OpenParen, "(".to_string(), 2),
+            (TokenKind::Sym, "X".to_string(), 3),
+            (TokenKind::CloseParen, ")".to_string(), 4),
+            (TokenKind::Equals, "=".to_string(), 5),
+            (TokenKind::Op, "-".to_string(), 6),
+            (TokenKind::Sym, "X".to_string(), 7),
+            (TokenKind::Op, "==".to_string(), 9),
+            (TokenKind::Sym, "Y".to_string(), 12),
+            (TokenKind::Equals, "=".to_string(), 14),
+            (TokenKind::Op, ">".to_string(), 15),
+            (TokenKind::End, "".to_string(), 16),
+        ]);
+    }
+
+    #[test]
+    pub fn numbers() {
+        assert_eq!(lex("s(10) 2x"), vec![
+            (TokenKind::Sym, "s".to_string(), 1, 1),
+            (TokenKind::OpenParen, "(".to_string(), 1, 2),
+            (TokenKind::Number, "10".to_string(), 1, 3),
+            (TokenKind::CloseParen, ")".to_string(), 1, 5),
+            (TokenKind::Sym, "2x".to_string(), 1, 7),
+            (TokenKind::End, "".to_string(), 1, 9),
+        ]);
+    }
+
+    #[test]
+    pub fn strings() {
+        assert_eq!(lex("include \"lib/nat.noq\" \"a"), vec![
+            (TokenKind::Sym, "include".to_string(), 1, 1),
+            (TokenKind::Str, "lib/nat.noq".to_string(), 1, 9),
+            (TokenKind::UnclosedString, "\"".to_string(), 1, 23),
+        ]);
+    }
+
+    #[test]
+    pub fn unclosed_block_comment() {
+        assert_eq!(lex("a /* /* */"), vec![
+            (TokenKind::Sym, "a".to_string(), 1, 1),
+            (TokenKind::UnclosedComment, "/*".to_string(), 1, 3),
+        ]);
+    }
+}
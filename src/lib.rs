@@ -0,0 +1,2723 @@
+//! Noq is a simple expression transformer: expressions are shaped step by
+//! step with rewrite rules, the way the `nothing` binary does it in its REPL
+//! or over a whole script.
+//!
+//! A [`Context`] holds the rules, the notation and the shaping in progress.
+//! Its commands print their results to an [output](Context::set_output) of
+//! the caller's choice, and the goal they reach is its [`current_goal`](Context::current_goal).
+//! Terms are parsed with its notation, and its [`RuleSet`] rewrites them with
+//! any [`Strategy`]:
+//!
+//! ```
+//! use noq::{Context, Strategy};
+//!
+//! let mut context = Context::default();
+//! context.run_script("rule swap swap(pair(A, B)) = pair(B, A)", None)?;
+//! let expr = context.parse_expr("swap(pair(f(a), swap(pair(b, c))))")?;
+//! let rules = context.rule_set();
+//! let result = Strategy::BottomUp.apply(&expr, &mut |expr| rules.rewrite(expr));
+//! assert_eq!(result, Ok(Some(context.parse_expr("pair(pair(c, b), f(a))")?)));
+//! # Ok::<(), noq::Error>(())
+//! ```
+//!
+//! [`RuleSet::rewrite`] also evaluates the arithmetic [`Primitives`], and so
+//! do `eval`, the conditions of rules, `check confluence` and `simplify`. An
+//! `apply` only rewrites with the rule it names, so a derivation shows every
+//! step of it; `eval` is the step that evaluates the primitives.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::iter::Peekable;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// `println!` to the output of a [`Context`], which is stdout unless it was
+/// [set](Context::set_output) to something else
+macro_rules! outputln {
+    ($output:expr, $($arg:tt)*) => {
+        writeln!($output, $($arg)*).expect("Failed to write the output of a command")
+    };
+}
+
+pub mod lexer;
+mod bigint;
+mod primitives;
+mod termination;
+mod confluence;
+mod unify;
+mod completion;
+mod egraph;
+mod theories;
+mod signatures;
+mod prelude;
+
+use lexer::*;
+pub use bigint::BigInt;
+pub use primitives::Primitives;
+use termination::Order;
+use confluence::Derivation;
+use completion::Failure;
+use egraph::{Cost, EGraph, Limits, Stop};
+pub use theories::{Theories, Theory};
+pub use unify::{unify, Mismatch, Substitution};
+use signatures::{Signatures, VarSorts};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Expr {
+    Sym(String),
+    Var(String),
+    Num(BigInt),
+    Fun(String, Vec<Expr>)
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UnexpectedToken(TokenKindSet, Token),
+    RuleAlreadyExists(String, Loc, Loc),
+    RuleDoesNotExist(String, Loc),
+    AlreadyShaping(Loc),
+    NoShapingInPlace(Loc),
+    StepLimitExceeded(Strategy, Loc),
+    InvalidPath(Path, Loc),
+    NoMatchAt(Path, Loc),
+    IrreversibleRule(Vec<String>, Loc),
+    NothingToUndo(Loc),
+    NothingToRedo(Loc),
+    UnboundVariables(Vec<String>, Loc),
+    NotAnEquation(Loc),
+    UnprovenEquation(Loc),
+    NonAssociativeOperator(Token),
+    InvalidNumber(Token),
+    InvalidSetting(Token),
+    ConditionNotBoolean(Expr, Loc),
+    InvalidCheck(Token),
+    VariableFunctor(Token),
+    /// The symbol, the arity of its signature, the amount of arguments it got
+    /// where, and where the signature comes from
+    ArityMismatch(String, usize, usize, Loc, Loc),
+    /// The sort of the expression at the location and the one expected there
+    SortMismatch(String, String, Loc),
+    /// The file and why it can't be read
+    CannotInclude(String, String, Loc),
+    IncludeCycle(String, Loc),
+    NoModuleToClose(Loc),
+    /// The module and where it was opened
+    UnclosedModule(String, Loc),
+    NothingToImport(String, Loc),
+    UnknownLibrary(Token),
+    /// Where the doc comments start
+    DocWithoutRule(Loc),
+}
+
+impl Error {
+    /// Where the error happened
+    pub fn loc(&self) -> &Loc {
+        match self {
+            Error::UnexpectedToken(_, token)
+            | Error::NonAssociativeOperator(token)
+            | Error::InvalidNumber(token)
+            | Error::InvalidSetting(token)
+            | Error::InvalidCheck(token)
+            | Error::VariableFunctor(token)
+            | Error::UnknownLibrary(token) => &token.loc,
+            Error::RuleAlreadyExists(_, loc, _)
+            | Error::RuleDoesNotExist(_, loc)
+            | Error::AlreadyShaping(loc)
+            | Error::NoShapingInPlace(loc)
+            | Error::StepLimitExceeded(_, loc)
+            | Error::InvalidPath(_, loc)
+            | Error::NoMatchAt(_, loc)
+            | Error::IrreversibleRule(_, loc)
+            | Error::NothingToUndo(loc)
+            | Error::NothingToRedo(loc)
+            | Error::UnboundVariables(_, loc)
+            | Error::NotAnEquation(loc)
+            | Error::UnprovenEquation(loc)
+            | Error::ConditionNotBoolean(_, loc)
+            | Error::ArityMismatch(_, _, _, loc, _)
+            | Error::SortMismatch(_, _, loc)
+            | Error::CannotInclude(_, _, loc)
+            | Error::IncludeCycle(_, loc)
+            | Error::NoModuleToClose(loc)
+            | Error::UnclosedModule(_, loc)
+            | Error::NothingToImport(_, loc)
+            | Error::DocWithoutRule(loc) => loc,
+        }
+    }
+
+    /// Another location that explains the error, with what is there
+    pub fn note(&self) -> Option<(&Loc, &'static str)> {
+        match self {
+            Error::RuleAlreadyExists(_, _, old_loc) => Some((old_loc, "Previous definition is located here")),
+            Error::ArityMismatch(_, _, _, _, signature_loc) => Some((signature_loc, "Its arity comes from here")),
+            _ => None,
+        }
+    }
+}
+
+/// The message of the error, without its location
+impl DisplayWithNotation for Error {
+    fn fmt_with(&self, f: &mut fmt::Formatter, notation: &Notation) -> fmt::Result {
+        match self {
+            Error::UnexpectedToken(expected_kinds, actual_token) => {
+                write!(f, "expected {} but got {} '{}'", expected_kinds, actual_token.kind, actual_token.text)
+            }
+            Error::RuleAlreadyExists(name, _, _) => write!(f, "redefinition of existing rule {}", name),
+            Error::RuleDoesNotExist(name, _) => write!(f, "rule {} does not exist", name),
+            Error::AlreadyShaping(_) => {
+                write!(f, "already shaping an expression. Finish the current shaping with {} first.", TokenKind::Done)
+            }
+            Error::NoShapingInPlace(_) => write!(f, "no shaping in place."),
+            Error::StepLimitExceeded(strategy, _) => {
+                write!(f, "strategy `{}` did not reach a fixpoint within its step limit", strategy)
+            }
+            Error::InvalidPath(path, _) => write!(f, "there is no subterm at position {}", path),
+            Error::NoMatchAt(path, _) => write!(f, "rule does not match at position {}", path),
+            Error::IrreversibleRule(vars, _) => {
+                write!(f, "rule can't be applied in reverse, its body does not bind {}", vars.join(", "))
+            }
+            Error::NothingToUndo(_) => write!(f, "nothing to undo."),
+            Error::NothingToRedo(_) => write!(f, "nothing to redo."),
+            Error::UnboundVariables(vars, _) => {
+                write!(f, "the shaped expression introduced variables {} that the initial one does not bind", vars.join(", "))
+            }
+            Error::NotAnEquation(_) => write!(f, "the shaped expression is not an equation, it has no sides."),
+            Error::UnprovenEquation(_) => write!(f, "the sides of the equation are not equal yet."),
+            Error::NonAssociativeOperator(op) => {
+                write!(f, "operator {} is non-associative, use parentheses to group it", op.text)
+            }
+            Error::InvalidNumber(token) => write!(f, "number {} is too large", token.text),
+            Error::InvalidSetting(token) => write!(f, "invalid setting `{}`", token.text),
+            Error::ConditionNotBoolean(condition, _) => {
+                write!(f, "condition of the rule normalized to `{}` instead of true or false", condition.with(notation))
+            }
+            Error::InvalidCheck(token) => {
+                write!(f, "unknown property `{}` to check, expected `termination` or `confluence`", token.text)
+            }
+            Error::VariableFunctor(token) => {
+                write!(f, "variable {} can't be applied to arguments, functor names start with a lowercase letter", token.text)
+            }
+            Error::ArityMismatch(name, arity, args, _, _) => write!(f, "{} takes {} arguments but got {}", name, arity, args),
+            Error::SortMismatch(sort, expected, _) => write!(f, "expression of sort {} where {} is expected", sort, expected),
+            Error::CannotInclude(file_path, reason, _) => write!(f, "can't include {}: {}", file_path, reason),
+            Error::IncludeCycle(file_path, _) => write!(f, "{} is already being included", file_path),
+            Error::NoModuleToClose(_) => write!(f, "no module to close"),
+            Error::UnclosedModule(name, _) => write!(f, "module {} is never closed", name),
+            Error::NothingToImport(name, _) => write!(f, "there is no rule or module {} to import", name),
+            Error::UnknownLibrary(name) => {
+                let names: Vec<&str> = prelude::names().collect();
+                write!(f, "there is no module {} in the standard library, expected one of {}", name.text, names.join(", "))
+            }
+            Error::DocWithoutRule(_) => write!(f, "doc comments have to come right before a rule"),
+        }
+    }
+}
+
+/// The tokens that an expression can start with, besides the minus of a negative number
+const OPERAND_START: TokenKindSet = TokenKindSet::single(TokenKind::Sym).set(TokenKind::Number).set(TokenKind::OpenParen);
+
+fn starts_operand(token: &Token) -> bool {
+    OPERAND_START.contains(token.kind) || (token.kind == TokenKind::Op && token.text == "-")
+}
+
+impl Expr {
+    /// Pattern variables are the symbols that start with an uppercase letter,
+    /// everything else is a constant that only matches itself.
+    fn var_or_sym(name: &str) -> Self {
+        if name.starts_with(char::is_uppercase) {
+            Expr::Var(name.to_string())
+        } else {
+            Expr::Sym(name.to_string())
+        }
+    }
+
+    pub fn parse(lexer: &mut Peekable<impl Iterator<Item=Token>>, notation: &Notation) -> Result<Self, Error> {
+        Self::parse_located(lexer, notation, &mut Vec::new())
+    }
+
+    /// Also pushes the location of every node of the expression to `locs`, in preorder
+    fn parse_located(lexer: &mut Peekable<impl Iterator<Item=Token>>, notation: &Notation, locs: &mut Vec<Loc>) -> Result<Self, Error> {
+        match Self::parse_trailing(lexer, notation, locs)? {
+            (expr, None) => Ok(expr),
+            (_, Some(_)) => Err(Error::UnexpectedToken(OPERAND_START, lexer.next().expect("Completely exhausted lexer"))),
+        }
+    }
+
+    /// Like [`Expr::parse_located`], but an operator that no operand follows ends
+    /// the expression and is returned with it, like the `*` strategy after the
+    /// body of an inline rule
+    fn parse_trailing(lexer: &mut Peekable<impl Iterator<Item=Token>>, notation: &Notation, locs: &mut Vec<Loc>) -> Result<(Self, Option<Token>), Error> {
+        let mut trailing = None;
+        let expr = Self::parse_infix(lexer, notation, 0, locs, &mut trailing)?;
+        Ok((expr, trailing))
+    }
+
+    /// Precedence climbing over the operators declared in `notation`. Operators
+    /// that were not declared end the expression.
+    fn parse_infix(lexer: &mut Peekable<impl Iterator<Item=Token>>, notation: &Notation, min_precedence: usize, locs: &mut Vec<Loc>, trailing: &mut Option<Token>) -> Result<Self, Error> {
+        let lhs_start = locs.len();
+        let mut lhs = Self::parse_primary(lexer, notation, locs)?;
+        while let Some(fixity) = lexer.peek()
+            .filter(|t| trailing.is_none() && t.kind == TokenKind::Op)
+            .and_then(|t| notation.operators.borrow().get(&t.text).copied())
+            .filter(|fixity| fixity.precedence >= min_precedence)
+        {
+            let op = lexer.next().expect("Completely exhausted lexer");
+            if !lexer.peek().is_some_and(starts_operand) {
+                *trailing = Some(op);
+                break
+            }
+            // The operator is the parent of everything parsed so far
+            locs.insert(lhs_start, op.loc.clone());
+            let rhs_precedence = match fixity.assoc {
+                Assoc::Right => fixity.precedence,
+                Assoc::Left | Assoc::None => fixity.precedence + 1,
+            };
+            let rhs = Self::parse_infix(lexer, notation, rhs_precedence, locs, trailing)?;
+            lhs = Expr::Fun(op.text, vec![lhs, rhs]);
+
+            if fixity.assoc == Assoc::None {
+                let same_precedence = |t: &Token| {
+                    t.kind == TokenKind::Op && notation.operators.borrow().get(&t.text).map(|f| f.precedence) == Some(fixity.precedence)
+                };
+                if let Some(next_op) = lexer.next_if(same_precedence) {
+                    return Err(Error::NonAssociativeOperator(next_op))
+                }
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn parse_primary(lexer: &mut Peekable<impl Iterator<Item=Token>>, notation: &Notation, locs: &mut Vec<Loc>) -> Result<Self, Error> {
+        use TokenKind::*;
+        let name = lexer.next().expect("Completely exhausted lexer");
+        if matches!(name.kind, Sym | Number | Op) {
+            locs.push(name.loc.clone());
+        }
+
+        match name.kind {
+            Sym => {
+                if lexer.next_if(|t| t.kind == OpenParen).is_some() {
+                    if matches!(Expr::var_or_sym(&name.text), Expr::Var(_)) {
+                        return Err(Error::VariableFunctor(name))
+                    }
+                    let mut args = Vec::new();
+                    if lexer.next_if(|t| t.kind == CloseParen).is_some() {
+                        return Ok(Expr::Fun(name.text, args))
+                    }
+                    args.push(Self::parse_located(lexer, notation, locs)?);
+                    while lexer.next_if(|t| t.kind == Comma).is_some() {
+                        args.push(Self::parse_located(lexer, notation, locs)?);
+                    }
+                    let close_paren = lexer.next().expect("Completely exhausted lexer");
+                    if close_paren.kind == CloseParen {
+                        Ok(Expr::Fun(name.text, args))
+                    } else {
+                        Err(Error::UnexpectedToken(TokenKindSet::single(CloseParen), close_paren))
+                    }
+                } else {
+                    Ok(Expr::var_or_sym(&name.text))
+                }
+            },
+            Number => Ok(Expr::Num(parse_number(name)?)),
+            // Primitives can make numbers negative, which have to parse back
+            Op if name.text == "-" => {
+                let number = expect_token_kind(lexer, TokenKindSet::single(Number))?;
+                Ok(Expr::Num(-&parse_number::<BigInt>(number)?))
+            },
+            OpenParen => {
+                let expr = Self::parse_located(lexer, notation, locs)?;
+                expect_token_kind(lexer, TokenKindSet::single(CloseParen))?;
+                Ok(expr)
+            },
+            _ => Err(Error::UnexpectedToken(OPERAND_START, name))
+        }
+    }
+
+    /// Numbers are sugar for Peano numerals: `2` stands for `s(s(0))`. Numerals may
+    /// also be partially desugared, like `s(1)`.
+    pub fn as_numeral(&self) -> Option<BigInt> {
+        match self {
+            Expr::Num(n) => Some(n.clone()),
+            Expr::Fun(name, args) if name == "s" && args.len() == 1 => {
+                let n = args[0].as_numeral().filter(|n| !n.is_negative())?;
+                Some(&n + &BigInt::from(1))
+            },
+            _ => None,
+        }
+    }
+
+    /// Replaces every Peano numeral with the number it denotes
+    pub fn fold_numerals(&self) -> Expr {
+        if let Some(n) = self.as_numeral() {
+            return Expr::Num(n)
+        }
+        match self {
+            Expr::Fun(name, args) => Expr::Fun(name.clone(), args.iter().map(|arg| arg.fold_numerals()).collect()),
+            _ => self.clone(),
+        }
+    }
+
+    /// Names of the pattern variables in the order of their first occurrence
+    pub fn vars(&self) -> Vec<String> {
+        fn vars_impl(expr: &Expr, vars: &mut Vec<String>) {
+            match expr {
+                Expr::Sym(_) | Expr::Num(_) => {},
+                Expr::Var(name) => if !vars.contains(name) {
+                    vars.push(name.clone())
+                },
+                Expr::Fun(_, args) => for arg in args {
+                    vars_impl(arg, vars)
+                },
+            }
+        }
+
+        let mut vars = Vec::new();
+        vars_impl(self, &mut vars);
+        vars
+    }
+
+    pub fn subterm(&self, path: &[usize]) -> Option<&Expr> {
+        match path.split_first() {
+            None => Some(self),
+            Some((index, rest)) => match self {
+                Expr::Fun(_, args) => args.get(*index)?.subterm(rest),
+                Expr::Sym(_) | Expr::Var(_) | Expr::Num(_) => None,
+            }
+        }
+    }
+
+    /// Returns a copy of `self` where the subterm at `path` is replaced with `new_subterm`
+    pub fn replace_subterm(&self, path: &[usize], new_subterm: Expr) -> Option<Expr> {
+        match path.split_first() {
+            None => Some(new_subterm),
+            Some((index, rest)) => match self {
+                Expr::Fun(name, args) => {
+                    let mut new_args = args.clone();
+                    new_args[*index] = args.get(*index)?.replace_subterm(rest, new_subterm)?;
+                    Some(Expr::Fun(name.clone(), new_args))
+                },
+                Expr::Sym(_) | Expr::Var(_) | Expr::Num(_) => None,
+            }
+        }
+    }
+}
+
+/// Position of a subterm: the indices of the arguments to descend into, starting from the root
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path(pub Vec<usize>);
+
+impl Path {
+    /// Syntax: `root | index(.index)*`. Returns `None` if there is no path to parse.
+    fn parse(lexer: &mut Peekable<impl Iterator<Item=Token>>) -> Result<Option<Self>, Error> {
+        if lexer.next_if(|t| t.kind == TokenKind::Sym && t.text == "root").is_some() {
+            return Ok(Some(Path(vec![])))
+        }
+        let Some(first) = lexer.next_if(|t| t.kind == TokenKind::Number) else {
+            return Ok(None)
+        };
+        let mut indices = vec![parse_number(first)?];
+        while lexer.next_if(|t| t.kind == TokenKind::Dot).is_some() {
+            indices.push(parse_number(expect_token_kind(lexer, TokenKindSet::single(TokenKind::Number))?)?);
+        }
+        Ok(Some(Path(indices)))
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Path(indices) = self;
+        if indices.is_empty() {
+            return write!(f, "root")
+        }
+        for (i, index) in indices.iter().enumerate() {
+            if i > 0 { write!(f, ".")? }
+            write!(f, "{}", index)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Assoc {
+    Left,
+    Right,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Fixity {
+    precedence: usize,
+    assoc: Assoc,
+}
+
+/// How expressions are written down: which functors are infix operators and how tightly they bind
+#[derive(Debug, Default)]
+pub struct Notation {
+    /// Shared with the lexers of the context, which need to know what operators start with `=`
+    operators: Rc<RefCell<HashMap<String, Fixity>>>,
+    /// Display Peano numerals `s(...s(0))` as numbers
+    peano: bool,
+}
+
+/// Display that depends on the current [`Notation`]. The plain [`fmt::Display`]
+/// of such types uses an empty one, so every infix operator in it gets parenthesized.
+pub trait DisplayWithNotation {
+    fn fmt_with(&self, f: &mut fmt::Formatter, notation: &Notation) -> fmt::Result;
+
+    fn with<'a>(&'a self, notation: &'a Notation) -> WithNotation<'a, Self> where Self: Sized {
+        WithNotation(self, notation)
+    }
+}
+
+pub struct WithNotation<'a, T>(&'a T, &'a Notation);
+
+impl<T: DisplayWithNotation> fmt::Display for WithNotation<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let WithNotation(value, notation) = self;
+        value.fmt_with(f, notation)
+    }
+}
+
+impl Expr {
+    /// Applications of functors named like operators to two arguments are
+    /// displayed infix, and so are flattened ones to more than two
+    fn as_infix(&self) -> Option<(&str, &[Expr])> {
+        match self {
+            Expr::Fun(name, args) if args.len() >= 2 && name.chars().all(is_operator_char) => Some((name, args)),
+            _ => None,
+        }
+    }
+}
+
+impl DisplayWithNotation for Expr {
+    fn fmt_with(&self, f: &mut fmt::Formatter, notation: &Notation) -> fmt::Result {
+        if notation.peano {
+            if let Some(n) = self.as_numeral() {
+                return write!(f, "{}", n)
+            }
+        }
+
+        if let Some((op, args)) = self.as_infix() {
+            let operators = notation.operators.borrow();
+            let fixity = operators.get(op);
+            // The arguments in the middle of a flattened application are on neither side
+            let needs_parens = |arg: &Expr, side: Option<Assoc>| match arg.as_infix() {
+                None => false,
+                Some((arg_op, _)) => match (fixity, operators.get(arg_op)) {
+                    (Some(outer), Some(inner)) => {
+                        inner.precedence < outer.precedence
+                            || (inner.precedence == outer.precedence && !(Some(outer.assoc) == side && Some(inner.assoc) == side))
+                    },
+                    _ => true,
+                },
+            };
+            for (i, arg) in args.iter().enumerate() {
+                let side = match i {
+                    0 => Some(Assoc::Left),
+                    _ if i + 1 == args.len() => Some(Assoc::Right),
+                    _ => None,
+                };
+                if i > 0 { write!(f, " {} ", op)? }
+                if needs_parens(arg, side) {
+                    write!(f, "(")?;
+                    arg.fmt_with(f, notation)?;
+                    write!(f, ")")?;
+                } else {
+                    arg.fmt_with(f, notation)?;
+                }
+            }
+            return Ok(())
+        }
+
+        match self 
+        {
+            Expr::Sym(name) | Expr::Var(name) => write!(f, "{}", name),
+            Expr::Num(n) => write!(f, "{}", n),
+            Expr::Fun(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() 
+                {
+                    if i > 0 { write!(f, ", ")? }
+                    arg.fmt_with(f, notation)?;
+                }
+                write!(f, ")")
+            },
+        }
+    }
+}
+
+impl fmt::Display for Expr 
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result 
+    {
+        self.fmt_with(f, &Notation::default())
+    }
+}
+
+#[derive(Debug)]
+pub struct Rule 
+{
+    pub loc: Loc,
+    /// Lines of the doc comments that preceded the rule definition
+    pub doc: Vec<String>,
+    pub head: Expr,
+    pub body: Expr,
+    /// The rule only fires if this normalizes to `true`
+    pub condition: Option<Expr>,
+}
+
+/// All the rules and primitives of a context, the conditions of rules are
+/// normalized with them
+pub struct RuleSet<'a> {
+    /// Sorted by name, so the first one to match is always the same
+    rules: Vec<(&'a str, &'a Rule)>,
+    primitives: &'a Primitives,
+    /// Matching and the conditions are modulo these
+    theories: &'a Theories,
+    /// How many conditions are being normalized inside each other
+    condition_depth: Cell<usize>,
+}
+
+impl<'a> RuleSet<'a> {
+    pub fn new(rules: &'a HashMap<String, Rule>, primitives: &'a Primitives, theories: &'a Theories) -> Self {
+        let mut rules: Vec<(&str, &Rule)> = rules.iter().map(|(name, rule)| (name.as_str(), rule)).collect();
+        rules.sort_by_key(|(name, _)| *name);
+        Self {rules, primitives, theories, condition_depth: Cell::new(0)}
+    }
+
+    /// Rewrites `expr` at its root with the first rule that fires, or evaluates it as a primitive
+    pub fn rewrite(&self, expr: &Expr) -> Result<Option<Expr>, Expr> {
+        Ok(self.rewrite_named(expr)?.map(|(_, new_expr)| new_expr))
+    }
+
+    /// Same as [`RuleSet::rewrite`], but also tells the name of the rule that
+    /// fired, or `eval` for primitives
+    pub fn rewrite_named(&self, expr: &Expr) -> Result<Option<(&'a str, Expr)>, Expr> {
+        for (name, rule) in &self.rules {
+            if let Some(new_expr) = rule.rewrite(expr, self)? {
+                return Ok(Some((name, new_expr)))
+            }
+        }
+        Ok(self.primitives.eval(expr).map(|new_expr| ("eval", new_expr)))
+    }
+
+    /// Normalizes `condition` and tells whether it is `true` or `false`.
+    /// Anything else is returned as the error, and so is the condition itself
+    /// if it does not reach a normal form within the step limit, or needs more
+    /// than [`CONDITION_DEPTH_LIMIT`] conditions normalized inside each other.
+    pub fn holds(&self, condition: &Expr) -> Result<bool, Expr> {
+        let depth = self.condition_depth.get();
+        if depth >= CONDITION_DEPTH_LIMIT {
+            return Err(condition.clone())
+        }
+        self.condition_depth.set(depth + 1);
+        let normal_form = Strategy::Normalize(DEFAULT_STEP_LIMIT).apply(condition, &mut |expr| self.rewrite(expr));
+        self.condition_depth.set(depth);
+        match normal_form? {
+            Some(Expr::Sym(value)) if value == "true" => Ok(true),
+            Some(Expr::Sym(value)) if value == "false" => Ok(false),
+            Some(normal_form) => Err(normal_form),
+            None => Err(condition.clone()),
+        }
+    }
+}
+
+pub fn substitute_bindings(bindings: &Bindings, expr: &Expr) -> Expr 
+{
+    use Expr::*;
+    match expr 
+    {
+        Sym(_) | Num(_) => expr.clone(),
+
+        Var(name) => {
+            if let Some(value) = bindings.get(name) 
+            {
+                value.clone()
+            } else {
+                expr.clone()
+            }
+        },
+
+        // Functor names are never variables, patterns don't bind them either
+        Fun(name, args) => {
+            let mut new_args = Vec::new();
+            for arg in args 
+            {
+                new_args.push(substitute_bindings(bindings, arg))
+            }
+            Fun(name.clone(), new_args)
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(token: Token) -> Result<T, Error> {
+    token.text.parse().map_err(|_| Error::InvalidNumber(token))
+}
+
+fn expect_token_kind(lexer: &mut Peekable<impl Iterator<Item=Token>>, kinds: TokenKindSet) -> Result<Token, Error> {
+    let token = lexer.next().expect("Completely exhausted lexer");
+    if kinds.contains(token.kind) {
+        Ok(token)
+    } else {
+        Err(Error::UnexpectedToken(kinds, token))
+    }
+}
+
+/// Syntax: `[if <condition>]`, the optional tail of a rule definition
+fn parse_condition(lexer: &mut Peekable<impl Iterator<Item=Token>>, notation: &Notation, signatures: &mut Signatures, vars: &mut VarSorts) -> Result<Option<Expr>, Error> {
+    if lexer.next_if(|t| t.kind == TokenKind::Sym && t.text == "if").is_some() {
+        Ok(Some(signatures.parse(lexer, notation, vars, None)?.0))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Syntax: `name(.name)*`, continues a qualified name after its first part
+fn parse_qualified_name(lexer: &mut Peekable<impl Iterator<Item=Token>>, mut name: Token) -> Result<Token, Error> {
+    while lexer.next_if(|t| t.kind == TokenKind::Dot).is_some() {
+        let part = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?;
+        name.text.push('.');
+        name.text.push_str(&part.text);
+    }
+    Ok(name)
+}
+
+pub const DEFAULT_STEP_LIMIT: usize = 1000;
+
+/// How deep conditions of rules may nest while normalizing a condition, like
+/// with `rule r f(X) = a if f(X) == a`
+pub const CONDITION_DEPTH_LIMIT: usize = 64;
+
+/// How the matches of a rule are picked within an expression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// Rewrite every top-down, non-overlapping match in a single pass
+    All,
+    /// Rewrite only the first match of a top-down, left-to-right traversal
+    Once,
+    /// Rewrite every match in a single pass, arguments before their parents
+    BottomUp,
+    /// Keep rewriting the leftmost-innermost match, at most the given amount of steps
+    Innermost(usize),
+    /// Keep rewriting the leftmost-outermost match, at most the given amount of steps
+    Outermost(usize),
+    /// Repeat the `All` pass until the expression stops changing, at most the given amount of passes
+    Normalize(usize),
+}
+
+impl Strategy {
+    /// Syntax: `[once | bottomup | innermost [limit] | outermost [limit] | * [limit]]`
+    fn parse(lexer: &mut Peekable<impl Iterator<Item=Token>>) -> Result<Self, Error> {
+        fn parse_limit(lexer: &mut Peekable<impl Iterator<Item=Token>>) -> Result<usize, Error> {
+            match lexer.next_if(|t| t.kind == TokenKind::Number) {
+                Some(limit) => parse_number(limit),
+                None => Ok(DEFAULT_STEP_LIMIT),
+            }
+        }
+
+        if lexer.next_if(|t| t.kind == TokenKind::Op && t.text == "*").is_some() {
+            return Ok(Strategy::Normalize(parse_limit(lexer)?))
+        }
+
+        let name = lexer.next_if(|t| {
+            t.kind == TokenKind::Sym && matches!(t.text.as_str(), "once" | "bottomup" | "innermost" | "outermost")
+        });
+        match name.as_ref().map(|t| t.text.as_str()) {
+            None => Ok(Strategy::All),
+            Some("once") => Ok(Strategy::Once),
+            Some("bottomup") => Ok(Strategy::BottomUp),
+            Some("innermost") => Ok(Strategy::Innermost(parse_limit(lexer)?)),
+            Some("outermost") => Ok(Strategy::Outermost(parse_limit(lexer)?)),
+            Some(other) => unreachable!("Unexpected strategy {}", other),
+        }
+    }
+
+    /// Applies `rewrite` to the subterms of `expr` in the order defined by
+    /// the strategy. `rewrite` only has to try its rewrite at the root of the
+    /// subterm it is given, any error it reports stops the traversal. Returns
+    /// `None` if the step limit was exhausted before reaching a fixpoint.
+    pub fn apply<E>(&self, expr: &Expr, rewrite: &mut impl FnMut(&Expr) -> Result<Option<Expr>, E>) -> Result<Option<Expr>, E> {
+        match self {
+            Strategy::All => Self::all(expr, rewrite).map(Some),
+            Strategy::Once => Ok(Some(Self::outermost_step(expr, rewrite)?.unwrap_or_else(|| expr.clone()))),
+            Strategy::BottomUp => Self::bottom_up(expr, rewrite).map(Some),
+            Strategy::Innermost(limit) => Self::fixpoint(expr, *limit, |expr| Self::innermost_step(expr, rewrite)),
+            Strategy::Outermost(limit) => Self::fixpoint(expr, *limit, |expr| Self::outermost_step(expr, rewrite)),
+            Strategy::Normalize(limit) => Self::fixpoint(expr, *limit, |expr| {
+                let new_expr = Self::all(expr, rewrite)?;
+                Ok(if &new_expr == expr { None } else { Some(new_expr) })
+            }),
+        }
+    }
+
+    fn all<E>(expr: &Expr, rewrite: &mut impl FnMut(&Expr) -> Result<Option<Expr>, E>) -> Result<Expr, E> {
+        if let Some(new_expr) = rewrite(expr)? {
+            return Ok(new_expr)
+        }
+        match expr {
+            Expr::Sym(_) | Expr::Var(_) | Expr::Num(_) => Ok(expr.clone()),
+            Expr::Fun(name, args) => Ok(Expr::Fun(name.clone(), args.iter().map(|arg| Self::all(arg, rewrite)).collect::<Result<_, _>>()?)),
+        }
+    }
+
+    fn bottom_up<E>(expr: &Expr, rewrite: &mut impl FnMut(&Expr) -> Result<Option<Expr>, E>) -> Result<Expr, E> {
+        let expr = match expr {
+            Expr::Sym(_) | Expr::Var(_) | Expr::Num(_) => expr.clone(),
+            Expr::Fun(name, args) => Expr::Fun(name.clone(), args.iter().map(|arg| Self::bottom_up(arg, rewrite)).collect::<Result<_, _>>()?),
+        };
+        Ok(rewrite(&expr)?.unwrap_or(expr))
+    }
+
+    fn outermost_step<E>(expr: &Expr, rewrite: &mut impl FnMut(&Expr) -> Result<Option<Expr>, E>) -> Result<Option<Expr>, E> {
+        if let Some(new_expr) = rewrite(expr)? {
+            return Ok(Some(new_expr))
+        }
+        Self::step_in_args(expr, |arg| Self::outermost_step(arg, rewrite))
+    }
+
+    fn innermost_step<E>(expr: &Expr, rewrite: &mut impl FnMut(&Expr) -> Result<Option<Expr>, E>) -> Result<Option<Expr>, E> {
+        match Self::step_in_args(expr, |arg| Self::innermost_step(arg, rewrite))? {
+            Some(new_expr) => Ok(Some(new_expr)),
+            None => rewrite(expr),
+        }
+    }
+
+    /// Rewrites the first argument of `expr` that `step` succeeds on.
+    fn step_in_args<E>(expr: &Expr, mut step: impl FnMut(&Expr) -> Result<Option<Expr>, E>) -> Result<Option<Expr>, E> {
+        if let Expr::Fun(name, args) = expr {
+            for (i, arg) in args.iter().enumerate() {
+                if let Some(new_arg) = step(arg)? {
+                    let mut new_args = args.clone();
+                    new_args[i] = new_arg;
+                    return Ok(Some(Expr::Fun(name.clone(), new_args)))
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn fixpoint<E>(expr: &Expr, limit: usize, mut step: impl FnMut(&Expr) -> Result<Option<Expr>, E>) -> Result<Option<Expr>, E> {
+        let mut expr = expr.clone();
+        for _ in 0..limit {
+            match step(&expr)? {
+                Some(new_expr) => expr = new_expr,
+                None => return Ok(Some(expr)),
+            }
+        }
+        Ok(if step(&expr)?.is_none() { Some(expr) } else { None })
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Strategy::All => write!(f, "all"),
+            Strategy::Once => write!(f, "once"),
+            Strategy::BottomUp => write!(f, "bottomup"),
+            Strategy::Innermost(limit) => write!(f, "innermost {}", limit),
+            Strategy::Outermost(limit) => write!(f, "outermost {}", limit),
+            Strategy::Normalize(limit) => write!(f, "* {}", limit),
+        }
+    }
+}
+
+impl Rule {
+    /// The rule with its head and body swapped. Fails with the variables of the
+    /// head and the condition that the body does not bind, since they would be
+    /// left dangling.
+    pub fn reversed(&self) -> Result<Rule, Vec<String>> {
+        let body_vars = self.body.vars();
+        let mut unbound: Vec<String> = self.head.vars().into_iter().filter(|var| !body_vars.contains(var)).collect();
+        for var in self.condition.iter().flat_map(|condition| condition.vars()) {
+            if !body_vars.contains(&var) && !unbound.contains(&var) {
+                unbound.push(var);
+            }
+        }
+        if !unbound.is_empty() {
+            return Err(unbound)
+        }
+        Ok(Rule {
+            loc: self.loc.clone(),
+            doc: self.doc.clone(),
+            head: self.body.clone(),
+            body: self.head.clone(),
+            condition: self.condition.clone(),
+        })
+    }
+
+    /// Rewrites `expr` at its root. The condition of the rule, if any, is
+    /// normalized with `rules` and has to hold for the rule to fire, with any
+    /// of the ways the head matches modulo the theories. Fails with the normal
+    /// form of a condition that is neither `true` nor `false`.
+    pub fn rewrite(&self, expr: &Expr, rules: &RuleSet) -> Result<Option<Expr>, Expr> {
+        for bindings in pattern_matches(&self.head, expr, rules.theories) {
+            if let Some(condition) = &self.condition {
+                if !rules.holds(&substitute_bindings(&bindings, condition))? {
+                    continue
+                }
+            }
+            let new_expr = substitute_bindings(&bindings, &self.body);
+            return Ok(Some(rules.theories.flatten(&new_expr).into_owned()))
+        }
+        Ok(None)
+    }
+
+    /// Rewrites the matches of the rule picked by `strategy`. Primitives are
+    /// left alone, `eval` is the step that evaluates them.
+    pub fn apply(&self, expr: &Expr, strategy: Strategy, rules: &RuleSet) -> Result<Option<Expr>, Expr> {
+        strategy.apply(expr, &mut |expr| self.rewrite(expr, rules))
+    }
+
+    /// Rewrites exactly the subterm at `path`. Returns `None` if the rule does not fire there.
+    pub fn apply_at(&self, expr: &Expr, path: &Path, rules: &RuleSet) -> Result<Option<Expr>, Expr> {
+        let Path(indices) = path;
+        let Some(subterm) = expr.subterm(indices) else {
+            return Ok(None)
+        };
+        Ok(self.rewrite(subterm, rules)?.and_then(|new_subterm| expr.replace_subterm(indices, new_subterm)))
+    }
+
+    /// All the positions where the head matches, in top-down, left-to-right order
+    pub fn match_positions(&self, expr: &Expr, theories: &Theories) -> Vec<Path> {
+        fn match_positions_impl(rule: &Rule, expr: &Expr, theories: &Theories, path: &mut Vec<usize>, positions: &mut Vec<Path>) {
+            if pattern_match(&rule.head, expr, theories).is_some() {
+                positions.push(Path(path.clone()));
+            }
+            if let Expr::Fun(_, args) = expr {
+                for (i, arg) in args.iter().enumerate() {
+                    path.push(i);
+                    match_positions_impl(rule, arg, theories, path, positions);
+                    path.pop();
+                }
+            }
+        }
+
+        let mut positions = Vec::new();
+        match_positions_impl(self, expr, theories, &mut vec![], &mut positions);
+        positions
+    }
+}
+
+impl DisplayWithNotation for Rule {
+    fn fmt_with(&self, f: &mut fmt::Formatter, notation: &Notation) -> fmt::Result {
+        write!(f, "{} = {}", self.head.with(notation), self.body.with(notation))?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition.with(notation))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with(f, &Notation::default())
+    }
+}
+
+pub type Bindings = HashMap<String, Expr>;
+
+/// All the ways `pattern` matches `value` modulo the `theories` of the function symbols
+pub fn pattern_matches(pattern: &Expr, value: &Expr, theories: &Theories) -> Vec<Bindings> {
+    fn pattern_matches_impl(pattern: &Expr, value: &Expr, theories: &Theories, mut bindings: Bindings) -> Vec<Bindings> {
+        use Expr::*;
+        match (pattern, value) {
+            (Var(name), _) => {
+                if let Some(bound_value) = bindings.get(name) {
+                    if !theories.equal(bound_value, value) {
+                        return vec![]
+                    }
+                } else {
+                    bindings.insert(name.clone(), value.clone());
+                }
+                vec![bindings]
+            },
+            (Sym(name1), Sym(name2)) if name1 == name2 => vec![bindings],
+            (Num(n), _) if value.as_numeral().as_ref() == Some(n) => vec![bindings],
+            (Fun(name, args), Num(n)) if name == "s" && args.len() == 1 && *n > BigInt::zero() => {
+                pattern_matches_impl(&args[0], &Num(n - &BigInt::from(1)), theories, bindings)
+            },
+            (Fun(name1, args1), Fun(name2, args2)) if name1 == name2 => {
+                let mut matches = Vec::new();
+                for split in theories.splits(name1, args1.len(), args2.len()) {
+                    let mut partial = vec![bindings.clone()];
+                    for (arg1, group) in args1.iter().zip(&split) {
+                        let grouped;
+                        let arg2 = match group.as_slice() {
+                            [i] => &args2[*i],
+                            _ => {
+                                grouped = Fun(name2.clone(), group.iter().map(|&i| args2[i].clone()).collect());
+                                &grouped
+                            }
+                        };
+                        partial = partial.into_iter()
+                            .flat_map(|bindings| pattern_matches_impl(arg1, arg2, theories, bindings))
+                            .collect();
+                    }
+                    matches.extend(partial);
+                }
+                matches
+            },
+            _ => vec![],
+        }
+    }
+
+    pattern_matches_impl(&theories.flatten(pattern), &theories.flatten(value), theories, Bindings::new())
+}
+
+pub fn pattern_match(pattern: &Expr, value: &Expr, theories: &Theories) -> Option<Bindings> {
+    pattern_matches(pattern, value, theories).into_iter().next()
+}
+
+#[allow(unused_macros)]
+macro_rules! fun_args {
+    () => { vec![] };
+    ($name:ident) => { vec![expr!($name)] };
+    ($value:literal) => { vec![expr!($value)] };
+    ($name:ident,$($rest:tt)*) => {
+        {
+            let mut t = vec![expr!($name)];
+            t.append(&mut fun_args!($($rest)*));
+            t
+        }
+    };
+    ($value:literal,$($rest:tt)*) => {
+        {
+            let mut t = vec![expr!($value)];
+            t.append(&mut fun_args!($($rest)*));
+            t
+        }
+    };
+    ($name:ident($($args:tt)*)) => {
+        vec![expr!($name($($args)*))]
+    };
+    ($name:ident($($args:tt)*),$($rest:tt)*) => {
+        {
+            let mut t = vec![expr!($name($($args)*))];
+            t.append(&mut fun_args!($($rest)*));
+            t
+        }
+    }
+}
+
+#[allow(unused_macros)]
+macro_rules! expr {
+    ($name:ident) => {
+        Expr::var_or_sym(stringify!($name))
+    };
+    ($value:literal) => {
+        Expr::Num(BigInt::from($value))
+    };
+    ($name:ident($($args:tt)*)) => {
+        Expr::Fun(stringify!($name).to_string(), fun_args!($($args)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `source` with the notation of a new context
+    pub(crate) fn expr(source: &str) -> Expr {
+        Expr::parse(&mut Lexer::from_iter(source.chars()).peekable(), &Context::default().notation).unwrap()
+    }
+
+    /// An unconditional rule without docs
+    pub(crate) fn rule(head: &str, body: &str) -> Rule {
+        Rule {
+            loc: Loc { file_path: None, row: 1, col: 1 },
+            doc: vec![],
+            head: expr(head),
+            body: expr(body),
+            condition: None,
+        }
+    }
+
+    #[test]
+    pub fn rule_apply_all() {
+        let context = Context::default();
+        let rules = RuleSet::new(&context.rules, &context.primitives, &context.theories);
+        let swap = rule("swap(pair(A, B))", "pair(B, A)");
+
+        let input = expr! {
+            foo(swap(pair(f(a), g(b))),
+                swap(pair(q(c), z(d))))
+        };
+
+        let expected = expr! {
+            foo(pair(g(b), f(a)),
+                pair(z(d), q(c)))
+        };
+
+        assert_eq!(swap.apply(&input, Strategy::All, &rules), Ok(Some(expected)));
+    }
+
+    #[test]
+    pub fn constants_match_structurally() {
+        let context = Context::default();
+        let rules = RuleSet::new(&context.rules, &context.primitives, &context.theories);
+        let add0 = rule("add(z, A)", "A");
+
+        assert_eq!(add0.apply(&expr!(add(z, s(z))), Strategy::All, &rules), Ok(Some(expr!(s(z)))));
+        assert_eq!(add0.apply(&expr!(add(s(z), z)), Strategy::All, &rules), Ok(Some(expr!(add(s(z), z)))));
+        assert_eq!(pattern_match(&expr!(f(A, A)), &expr!(f(a, b)), &Theories::default()), None);
+    }
+
+    #[test]
+    pub fn functors_are_not_variables() {
+        let mut context = Context::default();
+        assert!(matches!(context.parse_expr("f(X, X(a))"), Err(Error::VariableFunctor(token)) if token.text == "X"));
+        assert!(matches!(context.process_line("rule r f(X) = X(a)"), Err(Error::VariableFunctor(_))));
+        let bindings = Bindings::from([("X".to_string(), expr!(g(b)))]);
+        assert_eq!(substitute_bindings(&bindings, &Expr::Fun("X".to_string(), vec![expr!(X)])), Expr::Fun("X".to_string(), vec![expr!(g(b))]));
+    }
+
+    #[test]
+    pub fn numbers_match_peano_numerals() {
+        let context = Context::default();
+        let rules = RuleSet::new(&context.rules, &context.primitives, &context.theories);
+        let add = rule("add(s(A), B)", "s(add(A, B))");
+        assert_eq!(add.apply(&expr!(add(2, 3)), Strategy::All, &rules), Ok(Some(expr!(s(add(1, 3))))));
+        assert_eq!(add.apply(&expr!(add(0, 3)), Strategy::All, &rules), Ok(Some(expr!(add(0, 3)))));
+
+        assert_eq!(pattern_match(&expr!(f(2)), &expr!(f(s(s(0)))), &Theories::default()), Some(Bindings::new()));
+        assert_eq!(expr!(s(s(add(0, s(1))))).fold_numerals(), expr!(s(s(add(0, 2)))));
+
+        let notation = Notation { peano: true, ..Notation::default() };
+        assert_eq!(expr!(f(s(s(0)), s(X))).with(&notation).to_string(), "f(2, s(X))");
+    }
+
+    #[test]
+    pub fn matching_modulo_theories() {
+        let mut context = Context::default();
+        let commands = [
+            "ac +",
+            "comm f",
+            "assoc app",
+            "rule zero X + 0 = X",
+            "rule twice X + X = 2 * X",
+            "rule first f(a, X) = X",
+            "rule last app(X, c) = X",
+        ];
+        for command in commands {
+            context.process_command(&mut Lexer::from_iter(command.chars()).peekable()).unwrap();
+        }
+        let rules = RuleSet::new(&context.rules, &context.primitives, &context.theories);
+        let normalize = |source: &str| {
+            let expr = Expr::parse(&mut Lexer::from_iter(source.chars()).peekable(), &context.notation).unwrap();
+            let expr = context.theories.flatten(&expr).into_owned();
+            let normal_form = Strategy::Normalize(DEFAULT_STEP_LIMIT).apply(&expr, &mut |expr| rules.rewrite(expr));
+            normal_form.unwrap().unwrap().with(&context.notation).to_string()
+        };
+        assert_eq!(normalize("c + (b + a)"), "c + b + a");
+        assert_eq!(normalize("b + (0 + a)"), "b + a");
+        assert_eq!(normalize("(a + b) + (b + a)"), "2 * (a + b)");
+        assert_eq!(normalize("f(b, a)"), "b");
+        assert_eq!(normalize("app(app(a, b), c)"), "app(a, b)");
+        assert_eq!(normalize("app(c, app(a, b))"), "app(c, a, b)");
+
+        for command in ["shape x * (a + b) = x * (b + a)", "done"] {
+            context.process_command(&mut Lexer::from_iter(command.chars()).peekable()).unwrap();
+        }
+
+        // The whole goal stays flattened, not only the rewritten subterm
+        for command in ["rule wrap g(X) = X + c", "shape a + g(b)", "apply wrap"] {
+            context.process_line(command).unwrap();
+        }
+        let sum = Expr::Fun("+".to_string(), vec![expr!(a), expr!(b), expr!(c)]);
+        assert_eq!(context.shaping.take().unwrap().current_goal(), &Goal::Expr(sum));
+
+        // Equality saturation matches modulo the theories too
+        let commands = ["shape f(b, a) = b", "simplify", "done", "shape (c + a) + (a + c) = 2 * (a + c)", "simplify", "done"];
+        for command in commands {
+            context.process_line(command).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn arities_are_checked() {
+        let mut context = Context::default();
+        let mut run = |command: &str| context.process_command(&mut Lexer::from_iter(command.chars()).peekable());
+        let loc = |col| Loc { file_path: None, row: 1, col };
+        run("sig add : 2").unwrap();
+        run("rule add0 add(0, X) = X").unwrap();
+        assert!(matches!(
+            run("rule bad add(X) = X"),
+            Err(Error::ArityMismatch(name, 2, 1, at, from)) if name == "add" && at == loc(10) && from == loc(5)
+        ));
+
+        // The rule is rejected as a whole, so `g` does not get an arity from its head
+        assert!(matches!(run("rule g1 g(a) = g(a, b)"), Err(Error::ArityMismatch(_, 1, 2, at, _)) if at == loc(16)));
+        run("rule g2 g(X, Y) = X").unwrap();
+        assert!(matches!(run("shape g(a, b) * a(c)"), Err(Error::ArityMismatch(name, 0, 1, at, _)) if name == "a" && at == loc(17)));
+        assert!(matches!(run("sig g : 3"), Err(Error::ArityMismatch(_, 2, 3, _, _))));
+    }
+
+    #[test]
+    pub fn sorts_are_checked() {
+        let mut context = Context::default();
+        let mut run = |command: &str| context.process_command(&mut Lexer::from_iter(command.chars()).peekable());
+        let loc = |col| Loc { file_path: None, row: 1, col };
+        for command in ["sig z : Nat", "sig s : Nat -> Nat", "sig add : Nat, Nat -> Nat", "sig true : Bool", "sig even : Nat -> Bool"] {
+            run(command).unwrap();
+        }
+        run("rule add0 add(z, X) = X").unwrap();
+        run("rule even0 even(z) = true").unwrap();
+        assert!(matches!(
+            run("rule bad add(X, z) = even(X)"),
+            Err(Error::SortMismatch(sort, expected, at)) if sort == "Bool" && expected == "Nat" && at == loc(22)
+        ));
+        // `X` gets its sort from the first position it occurs at
+        run("sig not : Bool -> Bool").unwrap();
+        assert!(matches!(
+            run("rule clash even(X) = not(X)"),
+            Err(Error::SortMismatch(sort, expected, at)) if sort == "Nat" && expected == "Bool" && at == loc(26)
+        ));
+        assert!(matches!(
+            run("shape add(X, z) = add(even(X), z)"),
+            Err(Error::SortMismatch(sort, _, at)) if sort == "Bool" && at == loc(23)
+        ));
+        assert!(matches!(run("sig add : Nat -> Nat"), Err(Error::ArityMismatch(_, 2, 1, _, _))));
+    }
+
+    #[test]
+    pub fn includes() {
+        let dir = std::env::temp_dir().join(format!("noq-includes-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        let write = |name: &str, source: &str| fs::write(dir.join(name), source).unwrap();
+        write("main.noq", "include \"lib/nat.noq\"\nshape add(0, a)\napply add0\ndone");
+        write("lib/nat.noq", "include \"zero.noq\"\nrule add0 add(0, X) = X");
+        write("lib/zero.noq", "rule zero z = 0");
+        write("cycle.noq", "include \"lib/cycle.noq\"");
+        write("lib/cycle.noq", "\ninclude \"../cycle.noq\"");
+        write("broken.noq", "include \"lib/broken.noq\"");
+        write("lib/broken.noq", "rule broken f(X) = X\nshape f(a, b)");
+        let main_path = dir.join("main.noq").display().to_string();
+        let mut context = Context::default();
+        context.run_script(&fs::read_to_string(&main_path).unwrap(), Some(&main_path)).unwrap();
+        assert!(context.rule("zero").is_some() && context.including.is_empty());
+
+        let run = |name: &str| {
+            let path = dir.join(name).display().to_string();
+            Context::default().run_script(&fs::read_to_string(&path).unwrap(), Some(&path)).unwrap_err()
+        };
+        let in_lib = |name: &str| Some(dir.join("lib").join(name).display().to_string());
+        let err = run("cycle.noq");
+        assert!(matches!(err, Error::IncludeCycle(..)));
+        assert_eq!((&err.loc().file_path, err.loc().row), (&in_lib("cycle.noq"), 2));
+        let err = run("broken.noq");
+        assert!(matches!(err, Error::ArityMismatch(..)));
+        assert_eq!((&err.loc().file_path, err.loc().row, err.loc().col), (&in_lib("broken.noq"), 2, 7));
+        assert!(matches!(run("lib/cycle.noq"), Error::IncludeCycle(..)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn modules() {
+        let mut context = Context::default();
+        let script = "
+            module nat {
+                rule add0 add(0, X) = X
+                module more { rule twice add(X, X) = mul(2, X) }
+                shape add(0, a)
+                    apply add0
+                done
+            }
+            module list {
+                rule add0 app(nil, X) = X
+                import nat.more.twice
+                apply twice
+            }
+        ";
+        assert!(matches!(context.run_script(script, None), Err(Error::NoShapingInPlace(_))));
+        assert!(context.rule("nat.add0").is_some() && context.rule("nat.more.twice").is_some() && context.rule("list.add0").is_some());
+        // The import inside `list` was dropped when the error closed it
+        let mut run = |command: &str| context.process_line(command);
+        run("shape add(0, app(nil, a))").unwrap();
+        assert!(matches!(run("apply add0"), Err(Error::RuleDoesNotExist(..))));
+        assert!(matches!(run("apply twice"), Err(Error::RuleDoesNotExist(..))));
+        run("apply nat.add0").unwrap();
+        run("import list as l").unwrap();
+        run("apply l.add0").unwrap();
+        run("undo").unwrap();
+        run("import list.add0 as nil0").unwrap();
+        run("apply nil0").unwrap();
+        run("import nat").unwrap();
+        run("apply more.twice").unwrap();
+        assert!(matches!(run("import sets"), Err(Error::NothingToImport(..))));
+        assert!(matches!(run("}"), Err(Error::NoModuleToClose(_))));
+        assert!(matches!(context.run_script("module open {", None), Err(Error::UnclosedModule(name, _)) if name == "open"));
+        assert!(context.scope.modules.is_empty());
+    }
+
+    #[test]
+    pub fn recovers_from_errors() {
+        let mut context = Context::default();
+        let script = "
+rule add0 add(0, X) = X
+shape add(0, a, b)
+    apply add0
+done
+rule broken f(X) =
+/// Pulls the successor out
+rule add add(s(X), Y) = s(add(X, Y))
+module nat { rule twice f(a) = g( }
+shape add(s(0), 0)
+    apply nope
+done
+shape add(1, 0)
+    apply add
+    apply nat.twice
+";
+        let errors = context.run_script_recovering(script, None);
+        let rows: Vec<usize> = errors.iter().map(|err| err.loc().row).collect();
+        assert_eq!(rows, [3, 7, 9, 11, 15]);
+        assert!(matches!(errors[0], Error::ArityMismatch(..)));
+        assert!(matches!(errors[3], Error::RuleDoesNotExist(..)));
+        // The doc comment and the closing brace that ended the broken commands still count
+        assert_eq!(context.rule("add").unwrap().doc, ["Pulls the successor out"]);
+        assert!(context.scope.modules.is_empty() && context.shaping.is_none());
+        assert!(matches!(context.run_script(script, None), Err(Error::RuleAlreadyExists(..))));
+
+        // Scripts that end in the middle of a command
+        for script in ["rule a f(a) = a\nrule b g(X) =", "shape f(a)\napply"] {
+            let errors = Context::default().run_script_recovering(script, None);
+            assert!(matches!(&errors[..], [Error::UnexpectedToken(_, token)] if token.kind == TokenKind::End));
+        }
+    }
+
+    #[test]
+    pub fn output_goes_to_the_writer() {
+        #[derive(Clone, Default)]
+        struct Buffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let mut context = Context::default();
+        context.set_output(buffer.clone());
+        context.run_script("rule swap f(X, Y) = f(Y, X)\nshape f(a, b)\napply swap", None).unwrap();
+        assert_eq!(context.current_goal(), Some(&Goal::Expr(expr!(f(b, a)))));
+        assert_eq!(String::from_utf8(buffer.0.take()).unwrap(), " => f(a, b)\n => f(b, a)\n");
+    }
+
+    #[test]
+    pub fn loads() {
+        let mut context = Context::default();
+        context.run_script("module mine {\nload list\n}\nload nat", None).unwrap();
+        // Lists load the naturals themselves, at the top level and only once
+        assert!(context.rule("list.len").is_some() && context.rule("nat.add0").is_some());
+        assert_eq!(context.loaded, ["nat", "list"]);
+        let err = context.process_line("load sets").unwrap_err();
+        assert!(matches!(&err, Error::UnknownLibrary(name) if name.text == "sets"));
+
+        let mut context = Context::default();
+        context.process_line("rule r add(X) = X").unwrap();
+        for _ in 0..2 {
+            let err = context.process_line("load nat").unwrap_err();
+            assert!(matches!(err, Error::ArityMismatch(..)) && context.loaded.is_empty());
+        }
+    }
+
+    #[test]
+    pub fn negative_numbers_round_trip() {
+        let mut context = Context::default();
+        context.run_script("shape 3 - 5\neval\ndone as neg\nrule minus m(1)=-1", None).unwrap();
+        for (name, value) in [("neg", -2), ("minus", -1)] {
+            let rule = context.rule(name).unwrap();
+            assert_eq!(rule.body, Expr::Num(BigInt::from(value)));
+            let source = rule.with(&context.notation).to_string();
+            let (head, body) = source.split_once(" = ").unwrap();
+            assert_eq!(context.parse_expr(head).unwrap(), rule.head);
+            assert_eq!(context.parse_expr(body).unwrap(), rule.body);
+        }
+        let expr = context.parse_expr("3 - -2").unwrap();
+        assert_eq!(context.parse_expr(&expr.with(&context.notation).to_string()).unwrap(), expr);
+        assert!(matches!(context.parse_expr("-a"), Err(Error::UnexpectedToken(..))));
+    }
+
+    #[test]
+    pub fn primitives() {
+        let mut context = Context::default();
+        for command in ["shape 2 + 3 * 4 - div(7, 0 - 2)", "eval"] {
+            context.process_command(&mut Lexer::from_iter(command.chars()).peekable()).unwrap();
+        }
+        assert_eq!(context.shaping.take().unwrap().current_goal(), &Goal::Expr(expr!(18)));
+
+        for command in ["rule fact0 fact(0) = 1", "rule fact fact(N) = N * fact(N - 1)", "shape fact(25) == 15511210043330985984000000"] {
+            context.process_command(&mut Lexer::from_iter(command.chars()).peekable()).unwrap();
+        }
+        for _ in 0..25 {
+            for command in ["apply fact once", "eval"] {
+                context.process_command(&mut Lexer::from_iter(command.chars()).peekable()).unwrap();
+            }
+        }
+        for command in ["apply fact0 once", "eval"] {
+            context.process_command(&mut Lexer::from_iter(command.chars()).peekable()).unwrap();
+        }
+        assert_eq!(context.shaping.as_ref().unwrap().current_goal(), &Goal::Expr(expr!(true)));
+
+        // Only literals are evaluated, and only by `eval`
+        let mut context = Context::default();
+        for command in ["rule swap f(X, Y) = f(Y, X)", "shape f(1 + 2, s(s(0)) * s(0))", "apply swap", "eval"] {
+            context.process_line(command).unwrap();
+        }
+        let goal = context.parse_expr("f(s(s(0)) * s(0), 3)").unwrap();
+        assert_eq!(context.shaping.as_ref().unwrap().steps[1].goal, Goal::Expr(context.parse_expr("f(s(s(0)) * s(0), 1 + 2)").unwrap()));
+        assert_eq!(context.shaping.as_ref().unwrap().current_goal(), &Goal::Expr(goal));
+    }
+
+    #[test]
+    pub fn conditional_rules() {
+        let mut context = Context::default();
+        let commands = [
+            "rule ge0 ge(X, 0) = true",
+            "rule gez ge(0, s(Y)) = false",
+            "rule ges ge(s(X), s(Y)) = ge(X, Y)",
+            "rule big big(X) = yes if ge(X, 2)",
+            "rule small small(X) = yes if X * X < 10",
+            "shape f(big(3), big(1), small(3), small(4))",
+            "apply big",
+            "apply small",
+        ];
+        for command in commands {
+            context.process_command(&mut Lexer::from_iter(command.chars()).peekable()).unwrap();
+        }
+        assert_eq!(context.shaping.as_ref().unwrap().current_goal(), &Goal::Expr(expr!(f(yes, big(1), yes, small(4)))));
+        assert_eq!(context.rules["big"].with(&context.notation).to_string(), "big(X) = yes if ge(X, 2)");
+
+        let result = context.process_command(&mut Lexer::from_iter("apply rule big(X) = no if g(X)".chars()).peekable());
+        assert!(matches!(result, Err(Error::ConditionNotBoolean(condition, _)) if condition == expr!(g(1))));
+
+        // The condition needs itself to be normalized
+        let mut context = Context::default();
+        context.process_line("rule loop h(X) = a if h(X) == a").unwrap();
+        context.process_line("shape h(b)").unwrap();
+        assert!(matches!(context.process_line("apply loop"), Err(Error::ConditionNotBoolean(..))));
+    }
+
+    #[test]
+    pub fn simplify_equation() {
+        let mut context = Context::default();
+        let commands = [
+            "rule swap X + Y = Y + X",
+            "rule twice X + X = 2 * X",
+            "shape (a + b) + (b + a) = 2 * (b + a)",
+            "simplify",
+            "done",
+        ];
+        for command in commands {
+            context.process_command(&mut Lexer::from_iter(command.chars()).peekable()).unwrap();
+        }
+        assert!(context.shaping.is_none());
+    }
+
+    #[test]
+    pub fn rule_apply_strategies() {
+        let context = Context::default();
+        let rules = RuleSet::new(&context.rules, &context.primitives, &context.theories);
+        let f = rule("f(X)", "g(X)");
+
+        let input = expr!(h(f(f(a)), f(b)));
+
+        assert_eq!(f.apply(&input, Strategy::All, &rules), Ok(Some(expr!(h(g(f(a)), g(b))))));
+        assert_eq!(f.apply(&input, Strategy::Once, &rules), Ok(Some(expr!(h(g(f(a)), f(b))))));
+        assert_eq!(f.apply(&input, Strategy::BottomUp, &rules), Ok(Some(expr!(h(g(g(a)), g(b))))));
+        assert_eq!(f.apply(&input, Strategy::Innermost(1), &rules), Ok(None));
+        assert_eq!(f.apply(&input, Strategy::Innermost(3), &rules), Ok(Some(expr!(h(g(g(a)), g(b))))));
+        assert_eq!(f.apply(&input, Strategy::Outermost(3), &rules), Ok(Some(expr!(h(g(g(a)), g(b))))));
+        assert_eq!(f.apply(&input, Strategy::Normalize(2), &rules), Ok(Some(expr!(h(g(g(a)), g(b))))));
+
+        // f(X) = f(f(X)) never reaches a fixpoint
+        let grow = rule("f(X)", "f(f(X))");
+        assert_eq!(grow.apply(&expr!(f(a)), Strategy::Normalize(10), &rules), Ok(None));
+    }
+
+    #[test]
+    pub fn rule_apply_at_path() {
+        let context = Context::default();
+        let rules = RuleSet::new(&context.rules, &context.primitives, &context.theories);
+        let f = rule("f(X)", "g(X)");
+
+        let input = expr!(h(f(f(a)), f(b)));
+
+        assert_eq!(f.match_positions(&input, &Theories::default()), vec![Path(vec![0]), Path(vec![0, 0]), Path(vec![1])]);
+        assert_eq!(input.subterm(&[0, 0]), Some(&expr!(f(a))));
+        assert_eq!(input.subterm(&[0, 0, 1]), None);
+        assert_eq!(f.apply_at(&input, &Path(vec![0, 0]), &rules), Ok(Some(expr!(h(f(g(a)), f(b))))));
+        assert_eq!(f.apply_at(&input, &Path(vec![]), &rules), Ok(None));
+    }
+
+    #[test]
+    pub fn rule_reversed() {
+        let context = Context::default();
+        let rules = RuleSet::new(&context.rules, &context.primitives, &context.theories);
+        let add = rule("add(s(A), B)", "s(add(A, B))");
+        let reversed = add.reversed().unwrap();
+        assert_eq!(reversed.apply(&expr!(s(add(z, s(z)))), Strategy::All, &rules), Ok(Some(expr!(add(s(z), s(z))))));
+
+        let add0 = rule("add(z, A)", "z");
+        assert_eq!(add0.reversed().err(), Some(vec!["A".to_string()]));
+    }
+
+    #[test]
+    pub fn shaping_undo_redo() {
+        let mut shaping = Shaping::new("shape a".to_string(), Goal::Expr(expr!(a)));
+        assert!(!shaping.undo());
+
+        shaping.push("apply ab".to_string(), Goal::Expr(expr!(b)));
+        shaping.push("apply bc".to_string(), Goal::Expr(expr!(c)));
+        assert!(shaping.undo());
+        assert_eq!(shaping.current_goal(), &Goal::Expr(expr!(b)));
+        assert!(shaping.redo());
+        assert_eq!(shaping.current_goal(), &Goal::Expr(expr!(c)));
+
+        assert!(shaping.undo());
+        shaping.push("apply bd".to_string(), Goal::Expr(expr!(d)));
+        assert!(!shaping.redo());
+        assert_eq!(shaping.steps.len(), 3);
+    }
+
+    #[test]
+    pub fn done_as_registers_theorem() {
+        let mut context = Context::default();
+        for command in ["rule add0 add(z, A) = A", "rule add add(s(A), B) = s(add(A, B))", "shape add(s(z), X)", "apply add", "apply add0", "done as add1"] {
+            context.process_command(&mut Lexer::from_iter(command.chars()).peekable()).unwrap();
+        }
+
+        let add1 = context.rules.get("add1").unwrap();
+        assert_eq!(add1.head, expr!(add(s(z), X)));
+        assert_eq!(add1.body, expr!(s(X)));
+    }
+
+    #[test]
+    pub fn infix_operators() {
+        let mut context = Context::default();
+        for command in ["infixl 6 +", "infixl 7 *", "infixr 8 ^", "infix 4 =="] {
+            context.process_command(&mut Lexer::from_iter(command.chars()).peekable()).unwrap();
+        }
+        let parse = |source: &str| Expr::parse(&mut Lexer::from_iter(source.chars()).peekable(), &context.notation).unwrap();
+
+        let expr = parse("a + b * c ^ d ^ e + f(g + h)");
+        assert_eq!(expr, Expr::Fun("+".to_string(), vec![
+            Expr::Fun("+".to_string(), vec![
+                expr!(a),
+                Expr::Fun("*".to_string(), vec![
+                    expr!(b),
+                    Expr::Fun("^".to_string(), vec![expr!(c), Expr::Fun("^".to_string(), vec![expr!(d), expr!(e)])]),
+                ]),
+            ]),
+            expr!(f(g)).replace_subterm(&[0], Expr::Fun("+".to_string(), vec![expr!(g), expr!(h)])).unwrap(),
+        ]));
+        assert_eq!(expr.with(&context.notation).to_string(), "a + b * c ^ d ^ e + f(g + h)");
+
+        for source in ["(a + b) * c", "a + (b + c)", "(a ^ b) ^ c", "(a == b) == c"] {
+            assert_eq!(parse(source).with(&context.notation).to_string(), source);
+        }
+        assert_eq!(parse("a + b * c").to_string(), "a + (b * c)");
+        assert!(matches!(
+            Expr::parse(&mut Lexer::from_iter("a == b == c".chars()).peekable(), &context.notation),
+            Err(Error::NonAssociativeOperator(..))
+        ));
+    }
+
+    #[test]
+    pub fn operators_next_to_equals_and_comments() {
+        let mut context = Context::default();
+        context.run_script("infix 4 =>\nrule imp a=>b = a+// to b\nb", None).unwrap();
+        assert_eq!(context.parse_expr("a=>b").unwrap(), context.rule("imp").unwrap().head);
+        assert_eq!(context.parse_expr("a+b").unwrap(), context.rule("imp").unwrap().body);
+        assert!(matches!(context.process_line("infix 4 ="), Err(Error::UnexpectedToken(..))));
+    }
+
+    #[test]
+    pub fn inline_rules_before_strategies() {
+        let mut context = Context::default();
+        context.run_script("shape (a + 0) + 0\napply rule A + 0 = A *", None).unwrap();
+        assert_eq!(context.current_goal(), Some(&Goal::Expr(expr("a"))));
+        assert_eq!(context.shaping.as_ref().unwrap().steps[1].command, "apply rule A + 0 = A *");
+        context.process_line("undo").unwrap();
+        context.process_line("apply rule A + 0 = A * 0").unwrap();
+        assert_eq!(context.current_goal(), Some(&Goal::Expr(expr("(a + 0) * 0"))));
+        assert!(matches!(context.process_line("apply rule A * 0 = 0 +"), Err(Error::UnexpectedToken(_, token)) if token.kind == TokenKind::End));
+    }
+
+    #[test]
+    pub fn command_words_as_names() {
+        let mut context = Context::default();
+        context.run_script("rule load load(set(A), eval) = A\nshape load(set(a), eval)\napply load\ncomplete\nshow load", None).unwrap();
+        assert_eq!(context.current_goal(), Some(&Goal::Expr(expr("a"))));
+        assert!(matches!(context.process_line("set(a)"), Err(Error::UnexpectedToken(_, token)) if token.kind == TokenKind::OpenParen));
+    }
+
+    #[test]
+    pub fn doc_comments_attach_to_rules() {
+        let mut context = Context::default();
+        let mut lexer = Lexer::from_iter("/// Swaps a pair\n/// twice\nrule swap swap(pair(A, B)) = pair(B, A)".chars()).peekable();
+        context.process_command(&mut lexer).unwrap();
+        assert_eq!(context.rules.get("swap").unwrap().doc, vec!["Swaps a pair", "twice"]);
+    }
+
+    #[test]
+    pub fn doc_comments_only_before_rules() {
+        let mut context = Context::default();
+        let errors = context.run_script_recovering("/// Swaps a pair\nshape swap(a)\n/// Unused\n", None);
+        assert!(matches!(errors.as_slice(), [Error::DocWithoutRule(first), Error::DocWithoutRule(second)] if first.row == 1 && second.row == 3));
+        context.run_script("/// Swaps a pair\nrule swap swap(pair(A, B)) = pair(B, A)", None).unwrap();
+    }
+
+    #[test]
+    pub fn equation_goals() {
+        let mut context = Context::default();
+        for command in ["rule add0 add(z, A) = A", "rule add add(s(A), B) = s(add(A, B))", "shape add(s(z), s(z)) = add(z, s(s(z)))", "apply add left"] {
+            context.process_command(&mut Lexer::from_iter(command.chars()).peekable()).unwrap();
+        }
+        assert_eq!(context.shaping.as_ref().unwrap().current_goal(), &Goal::Eq(expr!(s(add(z, s(z)))), expr!(add(z, s(s(z))))));
+        assert!(matches!(
+            context.process_command(&mut Lexer::from_iter("done".chars()).peekable()),
+            Err(Error::UnprovenEquation(..))
+        ));
+
+        for command in ["apply add0", "done as two"] {
+            context.process_command(&mut Lexer::from_iter(command.chars()).peekable()).unwrap();
+        }
+        let two = context.rules.get("two").unwrap();
+        assert_eq!(two.head, expr!(add(s(z), s(z))));
+        assert_eq!(two.body, expr!(add(z, s(s(z)))));
+    }
+}
+
+/// What a shaping works on: either a single expression or both sides of an equation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Goal
+{
+    Expr(Expr),
+    /// The left and right sides are addressed as the subterms 0 and 1 of the goal
+    Eq(Expr, Expr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side
+{
+    Left,
+    Right,
+}
+
+impl Side
+{
+    fn index(self) -> usize
+    {
+        match self {
+            Side::Left => 0,
+            Side::Right => 1,
+        }
+    }
+}
+
+impl fmt::Display for Side
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            Side::Left => write!(f, "left"),
+            Side::Right => write!(f, "right"),
+        }
+    }
+}
+
+impl Goal
+{
+    fn subterm(&self, path: &[usize]) -> Option<&Expr>
+    {
+        match self {
+            Goal::Expr(expr) => expr.subterm(path),
+            Goal::Eq(lhs, rhs) => match path.split_first()? {
+                (0, rest) => lhs.subterm(rest),
+                (1, rest) => rhs.subterm(rest),
+                _ => None,
+            }
+        }
+    }
+
+    /// Rewrites exactly the subterm at `path`, see [`Rule::apply_at`]
+    fn apply_at(&self, rule: &Rule, path: &[usize], rules: &RuleSet) -> Result<Option<Goal>, Expr>
+    {
+        Ok(match self {
+            Goal::Expr(expr) => rule.apply_at(expr, &Path(path.to_vec()), rules)?.map(Goal::Expr),
+            Goal::Eq(lhs, rhs) => match path.split_first() {
+                Some((0, rest)) => rule.apply_at(lhs, &Path(rest.to_vec()), rules)?.map(|lhs| Goal::Eq(lhs, rhs.clone())),
+                Some((1, rest)) => rule.apply_at(rhs, &Path(rest.to_vec()), rules)?.map(|rhs| Goal::Eq(lhs.clone(), rhs)),
+                _ => None,
+            }
+        })
+    }
+
+    /// Applies `f` to the given side of an equation, or to both of them if
+    /// `side` is `None`. Single expressions have no sides and are always passed
+    /// to `f` as a whole.
+    fn map_sides<E>(&self, side: Option<Side>, mut f: impl FnMut(&Expr) -> Result<Option<Expr>, E>) -> Result<Option<Goal>, E>
+    {
+        match self {
+            Goal::Expr(expr) => Ok(f(expr)?.map(Goal::Expr)),
+            Goal::Eq(lhs, rhs) => {
+                let lhs = if side != Some(Side::Right) { f(lhs)? } else { Some(lhs.clone()) };
+                let rhs = if side != Some(Side::Left) { f(rhs)? } else { Some(rhs.clone()) };
+                Ok(lhs.zip(rhs).map(|(lhs, rhs)| Goal::Eq(lhs, rhs)))
+            }
+        }
+    }
+
+    /// Rewriting a subterm may put an application of an associative symbol
+    /// right under another one, this splices them together again
+    fn flatten(self, theories: &Theories) -> Goal
+    {
+        match self {
+            Goal::Expr(expr) => Goal::Expr(theories.flatten(&expr).into_owned()),
+            Goal::Eq(lhs, rhs) => Goal::Eq(theories.flatten(&lhs).into_owned(), theories.flatten(&rhs).into_owned()),
+        }
+    }
+
+    fn match_positions(&self, rule: &Rule, theories: &Theories) -> Vec<Path>
+    {
+        match self {
+            Goal::Expr(expr) => rule.match_positions(expr, theories),
+            Goal::Eq(lhs, rhs) => [lhs, rhs].iter().enumerate().flat_map(|(i, side)| {
+                rule.match_positions(side, theories).into_iter().map(move |Path(indices)| {
+                    Path([vec![i], indices].concat())
+                })
+            }).collect(),
+        }
+    }
+}
+
+impl DisplayWithNotation for Goal
+{
+    fn fmt_with(&self, f: &mut fmt::Formatter, notation: &Notation) -> fmt::Result
+    {
+        match self {
+            Goal::Expr(expr) => write!(f, "{}", expr.with(notation)),
+            Goal::Eq(lhs, rhs) => write!(f, "{} = {}", lhs.with(notation), rhs.with(notation)),
+        }
+    }
+}
+
+/// A step of a shaping: the command that was issued and the goal it produced
+struct Step
+{
+    command: String,
+    goal: Goal,
+}
+
+struct Shaping
+{
+    /// The first step is always the `shape` command itself
+    steps: Vec<Step>,
+    /// Steps taken back by `undo` that `redo` can bring back
+    undone: Vec<Step>,
+}
+
+impl Shaping
+{
+    fn new(command: String, goal: Goal) -> Self
+    {
+        Self {
+            steps: vec![Step {command, goal}],
+            undone: Vec::new(),
+        }
+    }
+
+    fn current_goal(&self) -> &Goal
+    {
+        &self.steps.last().expect("Shaping always has at least its initial step").goal
+    }
+
+    fn push(&mut self, command: String, goal: Goal)
+    {
+        self.undone.clear();
+        self.steps.push(Step {command, goal});
+    }
+
+    fn undo(&mut self) -> bool
+    {
+        if self.steps.len() <= 1 {
+            return false
+        }
+        self.undone.extend(self.steps.pop());
+        true
+    }
+
+    fn redo(&mut self) -> bool
+    {
+        if let Some(step) = self.undone.pop() {
+            self.steps.push(step);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn print_history(&self, notation: &Notation, output: &mut dyn Write)
+    {
+        for (i, step) in self.steps.iter().enumerate()
+        {
+            outputln!(output, "  {}: {} => {}", i, step.command, step.goal.with(notation));
+        }
+    }
+}
+
+/// Makes rules reachable without their qualified names
+enum Import
+{
+    /// The name stands for a rule, or for a module in front of the rest of a qualified name
+    Alias(String, String),
+    /// All the rules of the module
+    Open(String),
+}
+
+/// A module opened with `module` and not closed yet
+struct Module
+{
+    name: String,
+    loc: Loc,
+    /// The imports made before it was opened, the rest are dropped when it closes
+    imports: usize,
+}
+
+/// Where the commands are: the rules defined there are named after the
+/// enclosing modules, and other rules are reached through them and the imports
+#[derive(Default)]
+struct Scope
+{
+    /// The innermost one last
+    modules: Vec<Module>,
+    imports: Vec<Import>,
+}
+
+impl Scope
+{
+    /// What the rules defined in the current module are prefixed with
+    fn prefix(&self) -> String
+    {
+        self.modules.iter().map(|module| format!("{}.", module.name)).collect()
+    }
+
+    /// The qualified name that `name` refers to, if `exists`. Names are looked
+    /// up in the enclosing modules from the innermost one out, then through
+    /// the imports from the latest one.
+    fn resolve(&self, name: &str, exists: impl Fn(&str) -> bool) -> Option<String>
+    {
+        let scopes = (0..=self.modules.len()).rev().map(|depth| {
+            let prefix: String = self.modules[..depth].iter().map(|module| format!("{}.", module.name)).collect();
+            prefix + name
+        });
+        let imports = self.imports.iter().rev().filter_map(|import| match import {
+            Import::Alias(alias, target) => {
+                let rest = name.strip_prefix(alias.as_str())?;
+                (rest.is_empty() || rest.starts_with('.')).then(|| format!("{}{}", target, rest))
+            }
+            Import::Open(module) => Some(format!("{}.{}", module, name)),
+        });
+        scopes.chain(imports).find(|name| exists(name))
+    }
+
+    fn resolve_rule(&self, name: &Token, rules: &HashMap<String, Rule>) -> Result<String, Error>
+    {
+        self.resolve(&name.text, |name| rules.contains_key(name))
+            .ok_or_else(|| Error::RuleDoesNotExist(name.text.clone(), name.loc.clone()))
+    }
+
+    /// Drops the modules after the first `depth` ones, together with their imports
+    fn truncate(&mut self, depth: usize)
+    {
+        if let Some(module) = self.modules.get(depth)
+        {
+            self.imports.truncate(module.imports);
+            self.modules.truncate(depth);
+        }
+    }
+}
+
+/// Everything a script or a REPL session has defined so far. Its `apply`
+/// command rewrites with [`Rule::apply`], which leaves the primitives alone.
+pub struct Context
+{
+    rules: HashMap<String, Rule>,
+    /// Built-in functions that are evaluated alongside the rules
+    primitives: Primitives,
+    /// Declared with `assoc`, `comm` and `ac`
+    theories: Theories,
+    /// Declared with `sig` or inferred from the rules and shapes
+    signatures: Signatures,
+    /// The files whose commands are being run, the innermost one last
+    including: Vec<PathBuf>,
+    /// The modules of the standard library that were loaded already
+    loaded: Vec<String>,
+    scope: Scope,
+    notation: Notation,
+    shaping: Option<Shaping>,
+    /// Where the commands print their results
+    output: Box<dyn Write>,
+    quit: bool,
+}
+
+impl Default for Context
+{
+    fn default() -> Self
+    {
+        let builtin_operators = [
+            ("+", 6, Assoc::Left), ("-", 6, Assoc::Left), ("*", 7, Assoc::Left),
+            ("==", 4, Assoc::None), ("!=", 4, Assoc::None),
+            ("<", 4, Assoc::None), ("<=", 4, Assoc::None), (">", 4, Assoc::None), (">=", 4, Assoc::None),
+        ];
+        let operators = builtin_operators.into_iter()
+            .map(|(op, precedence, assoc)| (op.to_string(), Fixity {precedence, assoc}))
+            .collect();
+        Self {
+            rules: HashMap::new(),
+            primitives: Primitives::default(),
+            theories: Theories::default(),
+            signatures: Signatures::default(),
+            including: Vec::new(),
+            loaded: Vec::new(),
+            scope: Scope::default(),
+            notation: Notation {operators: Rc::new(RefCell::new(operators)), peano: false},
+            shaping: None,
+            output: Box::new(io::stdout()),
+            quit: false,
+        }
+    }
+}
+
+impl Context
+{
+    /// Runs the commands of `source` until its end or a `quit`, stopping at
+    /// the first error. The `file_path` ends up in the locations, and the
+    /// files it includes are relative to it.
+    pub fn run_script(&mut self, source: &str, file_path: Option<&str>) -> Result<(), Error>
+    {
+        match self.run_commands(source, file_path, false).into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Like [`run_script`](Context::run_script), but after an error it skips
+    /// to the next `rule` or `shape` and keeps going, so one pass reports all
+    /// the errors of the script. A shaping interrupted by an error is dropped.
+    pub fn run_script_recovering(&mut self, source: &str, file_path: Option<&str>) -> Vec<Error>
+    {
+        self.run_commands(source, file_path, true)
+    }
+
+    fn run_commands(&mut self, source: &str, file_path: Option<&str>, recover: bool) -> Vec<Error>
+    {
+        // Modules and doc comments as well, so skipping keeps the modules balanced and the docs with their rules
+        const RECOVERY_POINTS: TokenKindSet = TokenKindSet::single(TokenKind::Rule)
+            .set(TokenKind::Shape)
+            .set(TokenKind::CloseBrace)
+            .set(TokenKind::DocComment)
+            .set(TokenKind::End);
+
+        // `module` is lexed as a symbol like the other command words
+        fn is_recovery_point(token: &Token) -> bool
+        {
+            RECOVERY_POINTS.contains(token.kind) || token.kind == TokenKind::Sym && token.text == "module"
+        }
+
+        fn tokens<'a>(tokens: impl Iterator<Item=Token> + 'a) -> Peekable<Box<dyn Iterator<Item=Token> + 'a>>
+        {
+            (Box::new(tokens) as Box<dyn Iterator<Item=Token>>).peekable()
+        }
+
+        let mut lexer = self.lexer(source);
+        if let Some(file_path) = file_path
+        {
+            lexer.set_file_path(file_path);
+            self.including.push(PathBuf::from(file_path));
+        }
+        let mut lexer = tokens(lexer);
+        let depth = self.scope.modules.len();
+        let mut errors = Vec::new();
+        while !self.quit && lexer.peek().is_some_and(|t| t.kind != TokenKind::End)
+        {
+            if let Err(err) = self.process_command(&mut lexer)
+            {
+                if !recover
+                {
+                    errors.push(err);
+                    break
+                }
+                self.shaping = None;
+                // The command may have stopped at the token that starts the next one, or at the end of the
+                // script, which has to be seen again then
+                if let Error::UnexpectedToken(_, token) = &err
+                {
+                    let ours = token.loc.file_path.as_deref() == file_path;
+                    if ours && is_recovery_point(token)
+                    {
+                        let rest = std::mem::replace(&mut lexer, tokens(std::iter::empty()));
+                        lexer = tokens(std::iter::once(token.clone()).chain(rest));
+                    }
+                }
+                errors.push(err);
+                while lexer.peek().is_some_and(|t| !is_recovery_point(t))
+                {
+                    lexer.next();
+                }
+            }
+        }
+        if file_path.is_some()
+        {
+            self.including.pop();
+        }
+        // Modules don't outlive the script that opens them
+        if let Some(module) = self.scope.modules.get(depth)
+        {
+            errors.push(Error::UnclosedModule(module.name.clone(), module.loc.clone()));
+            self.scope.truncate(depth);
+        }
+        errors
+    }
+
+    /// Lexes `source` with the operators declared so far, and the ones that get declared while it's lexed
+    fn lexer<'a>(&self, source: &'a str) -> Lexer<std::str::Chars<'a>>
+    {
+        let mut lexer = Lexer::from_iter(source.chars());
+        let operators = Rc::clone(&self.notation.operators);
+        lexer.set_operators(move |op| operators.borrow().contains_key(op));
+        lexer
+    }
+
+    /// Runs a single command that has to take up the whole `line`
+    pub fn process_line(&mut self, line: &str) -> Result<(), Error>
+    {
+        let mut lexer = self.lexer(line).peekable();
+        self.process_command(&mut lexer)?;
+        expect_token_kind(&mut lexer, TokenKindSet::single(TokenKind::End))?;
+        Ok(())
+    }
+
+    /// Parses a term with the operators declared so far
+    pub fn parse_expr(&self, source: &str) -> Result<Expr, Error>
+    {
+        let mut lexer = self.lexer(source).peekable();
+        let expr = Expr::parse(&mut lexer, &self.notation)?;
+        expect_token_kind(&mut lexer, TokenKindSet::single(TokenKind::End))?;
+        Ok(expr)
+    }
+
+    pub fn rule(&self, name: &str) -> Option<&Rule>
+    {
+        self.rules.get(name)
+    }
+
+    /// Adds a rule as if it was defined by a `rule` command
+    pub fn add_rule(&mut self, name: String, rule: Rule) -> Result<(), Error>
+    {
+        if let Some(existing_rule) = self.rules.get(&name)
+        {
+            return Err(Error::RuleAlreadyExists(name, rule.loc, existing_rule.loc.clone()))
+        }
+        self.rules.insert(name, rule);
+        Ok(())
+    }
+
+    /// All the rules together with the primitives and the theories of the symbols
+    pub fn rule_set(&self) -> RuleSet<'_>
+    {
+        RuleSet::new(&self.rules, &self.primitives, &self.theories)
+    }
+
+    pub fn notation(&self) -> &Notation
+    {
+        &self.notation
+    }
+
+    pub fn is_shaping(&self) -> bool
+    {
+        self.shaping.is_some()
+    }
+
+    /// The goal the shaping in place is at, after all its steps
+    pub fn current_goal(&self) -> Option<&Goal>
+    {
+        self.shaping.as_ref().map(Shaping::current_goal)
+    }
+
+    /// Where the commands print their results, stdout by default
+    pub fn set_output(&mut self, output: impl Write + 'static)
+    {
+        self.output = Box::new(output);
+    }
+
+    /// Whether a `quit` command was issued
+    pub fn has_quit(&self) -> bool
+    {
+        self.quit
+    }
+
+    pub fn process_command(&mut self, lexer: &mut Peekable<impl Iterator<Item=Token>>) -> Result<(), Error>
+    {
+        let expected_tokens = TokenKindSet::empty()
+            .set(TokenKind::Rule)
+            .set(TokenKind::Shape)
+            .set(TokenKind::Apply)
+            .set(TokenKind::Done)
+            .set(TokenKind::Undo)
+            .set(TokenKind::Redo)
+            .set(TokenKind::History)
+            .set(TokenKind::Show)
+            .set(TokenKind::Infix)
+            .set(TokenKind::Infixl)
+            .set(TokenKind::Infixr)
+            .set(TokenKind::Set)
+            .set(TokenKind::Eval)
+            .set(TokenKind::Check)
+            .set(TokenKind::Unify)
+            .set(TokenKind::Complete)
+            .set(TokenKind::Simplify)
+            .set(TokenKind::Assoc)
+            .set(TokenKind::Comm)
+            .set(TokenKind::Ac)
+            .set(TokenKind::Sig)
+            .set(TokenKind::Include)
+            .set(TokenKind::Module)
+            .set(TokenKind::CloseBrace)
+            .set(TokenKind::Import)
+            .set(TokenKind::Load)
+            .set(TokenKind::Quit);
+        let mut doc = Vec::new();
+        let mut doc_loc = None;
+        while let Some(token) = lexer.next_if(|t| t.kind == TokenKind::DocComment)
+        {
+            doc_loc.get_or_insert(token.loc);
+            doc.push(token.text);
+        }
+        if let Some(doc_loc) = doc_loc
+        {
+            if lexer.peek().is_none_or(|t| t.kind != TokenKind::Rule)
+            {
+                return Err(Error::DocWithoutRule(doc_loc))
+            }
+        }
+        let keyword = match lexer.next_if(|t| t.kind == TokenKind::Sym)
+        {
+            Some(token) => match command_by_name(&token.text) {
+                Some(kind) => Token {kind, ..token},
+                None => return Err(Error::UnexpectedToken(expected_tokens, token)),
+            },
+            None => expect_token_kind(lexer, expected_tokens)?,
+        };
+        match keyword.kind
+        {
+            TokenKind::Rule => {
+                let name = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?;
+                let full_name = self.scope.prefix() + &name.text;
+                if let Some(existing_rule) = self.rules.get(&full_name)
+                {
+                    return Err(Error::RuleAlreadyExists(full_name, name.loc, existing_rule.loc.clone()))
+                }
+                // Symbols are only inferred from rules that are well-formed as a whole
+                let mut signatures = self.signatures.clone();
+                let mut vars = VarSorts::new();
+                let (head, sort) = signatures.parse(lexer, &self.notation, &mut vars, None)?;
+                expect_token_kind(lexer, TokenKindSet::single(TokenKind::Equals))?;
+                let (body, _) = signatures.parse(lexer, &self.notation, &mut vars, sort.as_ref())?;
+                let condition = parse_condition(lexer, &self.notation, &mut signatures, &mut vars)?;
+                let rule = Rule {
+                    loc: keyword.loc,
+                    doc,
+                    head,
+                    body,
+                    condition,
+                };
+                self.rules.insert(full_name, rule);
+                self.signatures = signatures;
+            }
+            TokenKind::Shape => {
+                if self.shaping.is_some()
+                {
+                    return Err(Error::AlreadyShaping(keyword.loc))
+                }
+
+                let mut signatures = self.signatures.clone();
+                let mut vars = VarSorts::new();
+                let (expr, sort) = signatures.parse(lexer, &self.notation, &mut vars, None)?;
+                let expr = self.theories.flatten(&expr).into_owned();
+                let goal = if lexer.next_if(|t| t.kind == TokenKind::Equals).is_some()
+                {
+                    let (rhs, _) = signatures.parse(lexer, &self.notation, &mut vars, sort.as_ref())?;
+                    Goal::Eq(expr, self.theories.flatten(&rhs).into_owned())
+                } else {
+                    Goal::Expr(expr)
+                };
+                self.signatures = signatures;
+                outputln!(self.output, " => {}", goal.with(&self.notation));
+                self.shaping = Some(Shaping::new(format!("shape {}", goal.with(&self.notation)), goal));
+            },
+            TokenKind::Apply => {
+                if let Some(shaping) = &mut self.shaping
+                {
+                    let goal = shaping.current_goal();
+                    let rules = RuleSet::new(&self.rules, &self.primitives, &self.theories);
+                    let expected_kinds = TokenKindSet::empty()
+                        .set(TokenKind::Sym)
+                        .set(TokenKind::Rule);
+                    let token = expect_token_kind(lexer, expected_kinds)?;
+                    let inline_rule;
+                    // An operator right after an inline rule, since its body can't end in one
+                    let mut trailing = None;
+                    let mut command = String::from("apply");
+                    let rule = match token.kind
+                    {
+                        TokenKind::Sym => {
+                            let name = parse_qualified_name(lexer, token)?;
+                            command.push_str(&format!(" {}", name.text));
+                            &self.rules[&self.scope.resolve_rule(&name, &self.rules)?]
+                        },
+                        TokenKind::Rule => {
+                            let mut signatures = self.signatures.clone();
+                            let mut vars = VarSorts::new();
+                            let (head, sort) = signatures.parse(lexer, &self.notation, &mut vars, None)?;
+                            expect_token_kind(lexer, TokenKindSet::single(TokenKind::Equals))?;
+                            let (body, _, body_trailing) = signatures.parse_trailing(lexer, &self.notation, &mut vars, sort.as_ref())?;
+                            trailing = body_trailing;
+                            let condition = if trailing.is_none() && lexer.next_if(|t| t.kind == TokenKind::Sym && t.text == "if").is_some()
+                            {
+                                let (condition, _, condition_trailing) = signatures.parse_trailing(lexer, &self.notation, &mut vars, None)?;
+                                trailing = condition_trailing;
+                                Some(condition)
+                            } else {
+                                None
+                            };
+                            self.signatures = signatures;
+                            inline_rule = Rule {loc: token.loc, doc: vec![], head, body, condition};
+                            command.push_str(&format!(" rule {}", inline_rule.with(&self.notation)));
+                            &inline_rule
+                        },
+                        _ => unreachable!("Expected {} but got {}", expected_kinds, token.kind),
+                    };
+
+                    let reversed_rule;
+                    let rule = if let Some(reverse) = lexer.next_if(|t| t.kind == TokenKind::Sym && t.text == "reverse")
+                    {
+                        reversed_rule = rule.reversed().map_err(|vars| Error::IrreversibleRule(vars, reverse.loc))?;
+                        command.push_str(" reverse");
+                        &reversed_rule
+                    } else {
+                        rule
+                    };
+
+                    let side = if let Some(token) = lexer.next_if(|t| t.kind == TokenKind::Sym && (t.text == "left" || t.text == "right"))
+                    {
+                        if !matches!(goal, Goal::Eq(..))
+                        {
+                            return Err(Error::NotAnEquation(token.loc));
+                        }
+                        command.push_str(&format!(" {}", token.text));
+                        Some(if token.text == "left" { Side::Left } else { Side::Right })
+                    } else {
+                        None
+                    };
+                    // Paths given after a side are relative to that side
+                    let side_prefix: Vec<usize> = side.iter().map(|side| side.index()).collect();
+
+                    let new_goal = if let Some(at) = lexer.next_if(|t| t.kind == TokenKind::Sym && t.text == "at")
+                    {
+                        if let Some(path) = Path::parse(lexer)?
+                        {
+                            let full_path = [side_prefix.as_slice(), &path.0].concat();
+                            if goal.subterm(&full_path).is_none() || (matches!(goal, Goal::Eq(..)) && full_path.is_empty())
+                            {
+                                return Err(Error::InvalidPath(path, at.loc));
+                            }
+                            command.push_str(&format!(" at {}", path));
+                            goal.apply_at(rule, &full_path, &rules)
+                                .map_err(|condition| Error::ConditionNotBoolean(condition, at.loc.clone()))?
+                                .ok_or(Error::NoMatchAt(path, at.loc))?
+                        } else {
+                            let positions: Vec<Path> = goal.match_positions(rule, &self.theories).into_iter()
+                                .filter_map(|Path(indices)| indices.strip_prefix(side_prefix.as_slice()).map(|rest| Path(rest.to_vec())))
+                                .collect();
+                            if positions.is_empty()
+                            {
+                                outputln!(self.output, "  no matches");
+                            }
+                            for path in positions
+                            {
+                                let full_path = [side_prefix.as_slice(), &path.0].concat();
+                                outputln!(self.output, "  {}: {}", path, goal.subterm(&full_path).unwrap().with(&self.notation));
+                            }
+                            return Ok(())
+                        }
+                    } else {
+                        let strategy = match &trailing
+                        {
+                            Some(op) if op.text == "*" => Strategy::Normalize(DEFAULT_STEP_LIMIT),
+                            Some(_) => return Err(Error::UnexpectedToken(OPERAND_START, lexer.next().expect("Completely exhausted lexer"))),
+                            None => Strategy::parse(lexer)?,
+                        };
+                        if trailing.is_some()
+                        {
+                            // A limit after it would be multiplied with the body of the rule instead
+                            command.push_str(" *");
+                        } else if strategy != Strategy::All
+                        {
+                            command.push_str(&format!(" {}", strategy));
+                        }
+                        goal.map_sides(side, |expr| rule.apply(expr, strategy, &rules))
+                            .map_err(|condition| Error::ConditionNotBoolean(condition, keyword.loc.clone()))?
+                            .ok_or(Error::StepLimitExceeded(strategy, keyword.loc))?
+                    }.flatten(&self.theories);
+                    outputln!(self.output, " => {}", new_goal.with(&self.notation));
+                    shaping.push(command, new_goal);
+                } else {
+                    return Err(Error::NoShapingInPlace(keyword.loc));
+                }
+            }
+            TokenKind::Done => {
+                let Some(shaping) = &self.shaping else {
+                    return Err(Error::NoShapingInPlace(keyword.loc))
+                };
+
+                if let Goal::Eq(lhs, rhs) = shaping.current_goal()
+                {
+                    if !self.theories.equal(&lhs.fold_numerals(), &rhs.fold_numerals())
+                    {
+                        return Err(Error::UnprovenEquation(keyword.loc))
+                    }
+                }
+
+                let theorem = if lexer.next_if(|t| t.kind == TokenKind::Sym && t.text == "as").is_some()
+                {
+                    let name = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?;
+                    let full_name = self.scope.prefix() + &name.text;
+                    if let Some(existing_rule) = self.rules.get(&full_name)
+                    {
+                        return Err(Error::RuleAlreadyExists(full_name, name.loc, existing_rule.loc.clone()))
+                    }
+                    let (head, body) = match (&shaping.steps[0].goal, shaping.current_goal()) {
+                        (Goal::Eq(lhs, rhs), _) => (lhs.clone(), rhs.clone()),
+                        (Goal::Expr(start), Goal::Expr(end)) => (start.clone(), end.clone()),
+                        (Goal::Expr(_), Goal::Eq(..)) => unreachable!("Shaping can't turn an expression into an equation"),
+                    };
+                    let head_vars = head.vars();
+                    let unbound: Vec<String> = body.vars().into_iter().filter(|var| !head_vars.contains(var)).collect();
+                    if !unbound.is_empty()
+                    {
+                        return Err(Error::UnboundVariables(unbound, name.loc))
+                    }
+                    Some((full_name, Rule {loc: keyword.loc, doc: vec![], head, body, condition: None}))
+                } else {
+                    None
+                };
+
+                shaping.print_history(&self.notation, &mut self.output);
+                self.shaping = None;
+                if let Some((name, rule)) = theorem
+                {
+                    outputln!(self.output, " rule {} {}", name, rule.with(&self.notation));
+                    self.rules.insert(name, rule);
+                }
+            }
+            TokenKind::Eval => {
+                let Some(shaping) = &mut self.shaping else {
+                    return Err(Error::NoShapingInPlace(keyword.loc))
+                };
+                let primitives = &self.primitives;
+                let Ok(new_goal) = shaping.current_goal()
+                    .map_sides(None, |expr| Strategy::BottomUp.apply(expr, &mut |expr| Ok::<_, Infallible>(primitives.eval(expr))));
+                let new_goal = new_goal.expect("The `bottomup` strategy has no step limit").flatten(&self.theories);
+                outputln!(self.output, " => {}", new_goal.with(&self.notation));
+                shaping.push("eval".to_string(), new_goal);
+            }
+            TokenKind::Undo | TokenKind::Redo => {
+                let Some(shaping) = &mut self.shaping else {
+                    return Err(Error::NoShapingInPlace(keyword.loc))
+                };
+                if keyword.kind == TokenKind::Undo && !shaping.undo()
+                {
+                    return Err(Error::NothingToUndo(keyword.loc))
+                }
+                if keyword.kind == TokenKind::Redo && !shaping.redo()
+                {
+                    return Err(Error::NothingToRedo(keyword.loc))
+                }
+                outputln!(self.output, " => {}", shaping.current_goal().with(&self.notation));
+            }
+            TokenKind::History => {
+                if let Some(shaping) = &self.shaping
+                {
+                    shaping.print_history(&self.notation, &mut self.output);
+                } else {
+                    return Err(Error::NoShapingInPlace(keyword.loc))
+                }
+            }
+            TokenKind::Infix | TokenKind::Infixl | TokenKind::Infixr => {
+                let precedence = parse_number(expect_token_kind(lexer, TokenKindSet::single(TokenKind::Number))?)?;
+                let mut op = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Op).set(TokenKind::Equals))?;
+                // A new operator that starts with `=` was lexed as an equals and the rest of it
+                if op.kind == TokenKind::Equals {
+                    let rest_loc = Loc {col: op.loc.col + 1, ..op.loc.clone()};
+                    match lexer.next_if(|t| t.kind == TokenKind::Op && t.loc == rest_loc) {
+                        Some(rest) => op.text.push_str(&rest.text),
+                        None => return Err(Error::UnexpectedToken(TokenKindSet::single(TokenKind::Op), op)),
+                    }
+                }
+                let assoc = match keyword.kind {
+                    TokenKind::Infixl => Assoc::Left,
+                    TokenKind::Infixr => Assoc::Right,
+                    _ => Assoc::None,
+                };
+                self.notation.operators.borrow_mut().insert(op.text, Fixity {precedence, assoc});
+            }
+            TokenKind::Assoc | TokenKind::Comm | TokenKind::Ac => {
+                let symbol_kinds = TokenKindSet::empty().set(TokenKind::Sym).set(TokenKind::Op);
+                let name = expect_token_kind(lexer, symbol_kinds)?;
+                let theory = match keyword.kind {
+                    TokenKind::Assoc => Theory::ASSOC,
+                    TokenKind::Comm => Theory::COMM,
+                    _ => Theory::AC,
+                };
+                self.theories.declare(name.text, theory);
+            }
+            TokenKind::Sig => {
+                let symbol_kinds = TokenKindSet::empty().set(TokenKind::Sym).set(TokenKind::Op);
+                let name = expect_token_kind(lexer, symbol_kinds)?;
+                expect_token_kind(lexer, TokenKindSet::single(TokenKind::Colon))?;
+                if let Some(arity) = lexer.next_if(|t| t.kind == TokenKind::Number)
+                {
+                    self.signatures.declare(name.text, parse_number(arity)?, None, name.loc)?;
+                    return Ok(())
+                }
+                let mut sorts = vec![expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?.text];
+                while lexer.next_if(|t| t.kind == TokenKind::Comma).is_some()
+                {
+                    sorts.push(expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?.text);
+                }
+                // A single sort without an arrow is the sort of a constant
+                let (arg_sorts, sort) = if lexer.next_if(|t| t.kind == TokenKind::Op && t.text == "->").is_some()
+                {
+                    (sorts, expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?.text)
+                } else if let [sort] = sorts.as_slice() {
+                    (vec![], sort.clone())
+                } else {
+                    let token = lexer.next().expect("Completely exhausted lexer");
+                    return Err(Error::UnexpectedToken(TokenKindSet::single(TokenKind::Op), token))
+                };
+                self.signatures.declare(name.text, arg_sorts.len(), Some((arg_sorts, sort)), name.loc)?;
+            }
+            TokenKind::Set => {
+                let name = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?;
+                if name.text != "peano"
+                {
+                    return Err(Error::InvalidSetting(name))
+                }
+                let value = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?;
+                self.notation.peano = match value.text.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(Error::InvalidSetting(value)),
+                };
+            }
+            TokenKind::Check => {
+                let property = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?;
+                match property.text.as_str() {
+                    "termination" => self.check_termination(lexer),
+                    "confluence" => self.check_confluence(&keyword.loc)?,
+                    _ => return Err(Error::InvalidCheck(property)),
+                }
+            }
+            TokenKind::Unify => {
+                let a = Expr::parse(lexer, &self.notation)?;
+                let b = Expr::parse(lexer, &self.notation)?;
+                match unify::unify(&a, &b) {
+                    Ok(substitution) => outputln!(self.output, " => {}", substitution.with(&self.notation)),
+                    Err(mismatch) => outputln!(self.output, "  not unifiable: {}", mismatch.with(&self.notation)),
+                }
+            }
+            TokenKind::Include => {
+                let file_path = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Str))?;
+                self.include(&file_path.text, &file_path.loc)?;
+            }
+            TokenKind::Load => {
+                let name = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?;
+                self.load(name)?;
+            }
+            TokenKind::Complete => self.complete(lexer, &keyword.loc)?,
+            TokenKind::Simplify => self.simplify(lexer, &keyword.loc)?,
+            TokenKind::Show => {
+                let name = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?;
+                let name = parse_qualified_name(lexer, name)?;
+                let full_name = self.scope.resolve_rule(&name, &self.rules)?;
+                let rule = &self.rules[&full_name];
+                for line in &rule.doc
+                {
+                    outputln!(self.output, "  /// {}", line);
+                }
+                outputln!(self.output, "  rule {} {}", full_name, rule.with(&self.notation));
+            }
+            TokenKind::Module => {
+                let name = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?;
+                expect_token_kind(lexer, TokenKindSet::single(TokenKind::OpenBrace))?;
+                self.scope.modules.push(Module {name: name.text, loc: keyword.loc, imports: self.scope.imports.len()});
+            }
+            TokenKind::CloseBrace => {
+                if self.scope.modules.is_empty()
+                {
+                    return Err(Error::NoModuleToClose(keyword.loc))
+                }
+                self.scope.truncate(self.scope.modules.len() - 1);
+            }
+            TokenKind::Import => {
+                let target = expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?;
+                let target = parse_qualified_name(lexer, target)?;
+                let alias = if lexer.next_if(|t| t.kind == TokenKind::Sym && t.text == "as").is_some()
+                {
+                    Some(expect_token_kind(lexer, TokenKindSet::single(TokenKind::Sym))?.text)
+                } else {
+                    None
+                };
+                let import = if let Some(rule) = self.scope.resolve(&target.text, |name| self.rules.contains_key(name))
+                {
+                    let last_part = target.text.rsplit('.').next().expect("Names have at least one part").to_string();
+                    Import::Alias(alias.unwrap_or(last_part), rule)
+                } else if let Some(module) = self.scope.resolve(&target.text, |name| self.rules.keys().any(|rule| rule.starts_with(&format!("{}.", name)))) {
+                    match alias {
+                        Some(alias) => Import::Alias(alias, module),
+                        None => Import::Open(module),
+                    }
+                } else {
+                    return Err(Error::NothingToImport(target.text, target.loc))
+                };
+                self.scope.imports.push(import);
+            }
+            TokenKind::Quit => {
+                self.quit = true;
+            }
+            _ => unreachable!("Expected {} but got {} '{}'", expected_tokens, keyword.kind, keyword.text),
+        }
+        Ok(())
+    }
+
+    /// Runs the commands of another file. Relative paths are resolved against
+    /// the directory of the file that includes it, or the working directory
+    /// in the REPL.
+    fn include(&mut self, file_path: &str, loc: &Loc) -> Result<(), Error>
+    {
+        let dir = self.including.last().and_then(|file| file.parent()).map(PathBuf::from).unwrap_or_default();
+        let file_path = dir.join(file_path);
+        let display_path = file_path.display().to_string();
+        let source = fs::read_to_string(&file_path)
+            .map_err(|err| Error::CannotInclude(display_path.clone(), err.to_string(), loc.clone()))?;
+        let canonical = fs::canonicalize(&file_path).ok();
+        if self.including.iter().any(|file| fs::canonicalize(file).ok() == canonical)
+        {
+            return Err(Error::IncludeCycle(display_path, loc.clone()))
+        }
+        self.run_script(&source, Some(&display_path))
+    }
+
+    /// Runs a module of the standard library unless it was loaded already.
+    /// It is loaded at the top level, whatever module is open at the moment.
+    fn load(&mut self, name: Token) -> Result<(), Error>
+    {
+        let Some(source) = prelude::source(&name.text) else {
+            return Err(Error::UnknownLibrary(name))
+        };
+        if self.loaded.contains(&name.text)
+        {
+            return Ok(())
+        }
+        let file_path = format!("std/{}.noq", name.text);
+        if self.including.iter().any(|file| file.as_os_str() == file_path.as_str())
+        {
+            return Err(Error::IncludeCycle(file_path, name.loc))
+        }
+        let scope = std::mem::take(&mut self.scope);
+        let result = self.run_script(source, Some(&file_path));
+        self.scope = scope;
+        // A module that failed to load can be loaded again once the conflict is gone
+        if result.is_ok()
+        {
+            self.loaded.push(name.text);
+        }
+        result
+    }
+
+    /// Syntax: `check termination [lpo | kbo]`, tries both orderings if none is given
+    fn check_termination(&mut self, lexer: &mut Peekable<impl Iterator<Item=Token>>)
+    {
+        let order = lexer.next_if(|t| t.kind == TokenKind::Sym && (t.text == "lpo" || t.text == "kbo"));
+        let orders = match order.as_ref().map(|t| t.text.as_str()) {
+            Some("lpo") => vec![Order::Lpo],
+            Some("kbo") => vec![Order::Kbo],
+            _ => vec![Order::Lpo, Order::Kbo],
+        };
+        let rules = RuleSet::new(&self.rules, &self.primitives, &self.theories);
+        for order in orders
+        {
+            match termination::check(order, &rules.rules) {
+                Ok(precedence) => outputln!(self.output, "  {}: terminating with precedence {}", order, precedence),
+                Err(name) => outputln!(self.output, "  {}: can't orient rule {} {}", order, name, self.rules[name].with(&self.notation)),
+            }
+        }
+    }
+
+    /// Syntax: `complete [lpo | kbo] [limit] [name...]`. Completes the named
+    /// rules, or all of them, taken as equations. Orders by LPO by default.
+    fn complete(&mut self, lexer: &mut Peekable<impl Iterator<Item=Token>>, loc: &Loc) -> Result<(), Error>
+    {
+        let order = match lexer.next_if(|t| t.kind == TokenKind::Sym && (t.text == "lpo" || t.text == "kbo")) {
+            Some(token) if token.text == "kbo" => Order::Kbo,
+            _ => Order::Lpo,
+        };
+        let step_limit = match lexer.next_if(|t| t.kind == TokenKind::Number) {
+            Some(limit) => parse_number(limit)?,
+            None => DEFAULT_STEP_LIMIT,
+        };
+        let mut names = Vec::new();
+        // The names end at the next command, which starts with a symbol too
+        while let Some(name) = lexer.next_if(|t| t.kind == TokenKind::Sym && command_by_name(&t.text).is_none())
+        {
+            let name = parse_qualified_name(lexer, name)?;
+            names.push(self.scope.resolve_rule(&name, &self.rules)?);
+        }
+        if names.is_empty()
+        {
+            names = self.rules.keys().cloned().collect();
+            names.sort();
+        }
+
+        let mut equations = Vec::new();
+        for name in names
+        {
+            let rule = &self.rules[&name];
+            if rule.condition.is_some()
+            {
+                outputln!(self.output, "  skipping conditional rule {}", name);
+                continue
+            }
+            equations.push((name, rule.head.clone(), rule.body.clone()));
+        }
+        let completion = completion::complete(order, equations, step_limit, loc);
+        match &completion.failure {
+            None => outputln!(self.output, "  convergent with {}:", order),
+            Some(Failure::Unorientable(lhs, rhs)) => {
+                outputln!(self.output, "  {} orients neither side of {} = {}, rules so far:", order, lhs.with(&self.notation), rhs.with(&self.notation));
+            }
+            Some(Failure::StepLimitExceeded) => outputln!(self.output, "  equations left after {} steps, rules so far:", step_limit),
+        }
+        for (name, rule) in &completion.rules
+        {
+            outputln!(self.output, "  rule {} {}", name, rule.with(&self.notation));
+        }
+        Ok(())
+    }
+
+    /// Syntax: `simplify [size | depth | weights name: weight, ...] [nodes limit] [time milliseconds]`.
+    /// Saturates the current goal with all the rules and extracts its cheapest form, by size by default.
+    fn simplify(&mut self, lexer: &mut Peekable<impl Iterator<Item=Token>>, loc: &Loc) -> Result<(), Error>
+    {
+        let Some(shaping) = &mut self.shaping else {
+            return Err(Error::NoShapingInPlace(loc.clone()))
+        };
+        let cost_name = lexer.next_if(|t| t.kind == TokenKind::Sym && matches!(t.text.as_str(), "size" | "depth" | "weights"));
+        let cost = match cost_name.as_ref().map(|t| t.text.as_str()) {
+            Some("depth") => Cost::Depth,
+            Some("weights") => {
+                let mut weights = Vec::new();
+                loop {
+                    let symbol_kinds = TokenKindSet::empty().set(TokenKind::Sym).set(TokenKind::Op);
+                    let symbol = expect_token_kind(lexer, symbol_kinds)?;
+                    expect_token_kind(lexer, TokenKindSet::single(TokenKind::Colon))?;
+                    let weight = parse_number(expect_token_kind(lexer, TokenKindSet::single(TokenKind::Number))?)?;
+                    weights.push((symbol.text, weight));
+                    if lexer.next_if(|t| t.kind == TokenKind::Comma).is_none() {
+                        break
+                    }
+                }
+                Cost::Weights(weights)
+            }
+            _ => Cost::Size,
+        };
+        let mut limits = Limits::default();
+        let mut command = format!("simplify {}", cost);
+        if lexer.next_if(|t| t.kind == TokenKind::Sym && t.text == "nodes").is_some()
+        {
+            limits.nodes = parse_number(expect_token_kind(lexer, TokenKindSet::single(TokenKind::Number))?)?;
+            command.push_str(&format!(" nodes {}", limits.nodes));
+        }
+        if lexer.next_if(|t| t.kind == TokenKind::Sym && t.text == "time").is_some()
+        {
+            let milliseconds = parse_number(expect_token_kind(lexer, TokenKindSet::single(TokenKind::Number))?)?;
+            limits.time = std::time::Duration::from_millis(milliseconds);
+            command.push_str(&format!(" time {}", milliseconds));
+        }
+
+        // Both sides of an equation share the e-graph, so they end up the same once they are proven equal
+        let mut egraph = EGraph::default();
+        let goal = shaping.current_goal();
+        let roots: Vec<egraph::Id> = match goal {
+            Goal::Expr(expr) => vec![egraph.add_expr(&self.theories.nest(expr))],
+            Goal::Eq(lhs, rhs) => vec![egraph.add_expr(&self.theories.nest(lhs)), egraph.add_expr(&self.theories.nest(rhs))],
+        };
+        let rules = RuleSet::new(&self.rules, &self.primitives, &self.theories);
+        let rules: Vec<&Rule> = rules.rules.iter().map(|(_, rule)| *rule).collect();
+        match egraph.saturate(&rules, &self.primitives, &self.theories, limits) {
+            Stop::Saturated(_) => {},
+            Stop::NodeLimit => outputln!(self.output, "  stopped at the limit of {} nodes", limits.nodes),
+            Stop::TimeLimit => outputln!(self.output, "  stopped at the time limit of {} ms", limits.time.as_millis()),
+        }
+        let mut extracted = roots.into_iter().map(|root| egraph.extract(root, &cost));
+        let new_goal = match goal {
+            Goal::Expr(_) => Goal::Expr(extracted.next().unwrap()),
+            Goal::Eq(..) => Goal::Eq(extracted.next().unwrap(), extracted.next().unwrap()),
+        }.flatten(&self.theories);
+        outputln!(self.output, " => {}", new_goal.with(&self.notation));
+        shaping.push(command, new_goal);
+        Ok(())
+    }
+
+    /// Syntax: `check confluence`
+    fn check_confluence(&mut self, loc: &Loc) -> Result<(), Error>
+    {
+        let rules = RuleSet::new(&self.rules, &self.primitives, &self.theories);
+        let pairs = confluence::critical_pairs(&rules.rules);
+        let mut non_joinable = 0;
+        for pair in &pairs
+        {
+            let left = Derivation::normalize(&pair.left, &rules)
+                .map_err(|condition| Error::ConditionNotBoolean(condition, loc.clone()))?;
+            let right = Derivation::normalize(&pair.right, &rules)
+                .map_err(|condition| Error::ConditionNotBoolean(condition, loc.clone()))?;
+            if left.normalized && right.normalized && rules.theories.equal(&left.end().fold_numerals(), &right.end().fold_numerals())
+            {
+                continue
+            }
+            non_joinable += 1;
+            outputln!(self.output, "  {} and {} overlap at {} of {}, not joinable:", pair.outer, pair.inner, pair.path, pair.peak.with(&self.notation));
+            left.print(&self.notation, &mut self.output);
+            right.print(&self.notation, &mut self.output);
+        }
+        if non_joinable == 0
+        {
+            outputln!(self.output, "  all {} critical pairs are joinable", pairs.len());
+        } else {
+            outputln!(self.output, "  {} of {} critical pairs are not joinable", non_joinable, pairs.len());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+//! The standard library: modules of rules that are embedded in the binary
+//! and run with `load`. Their sources live in `std/`, and every module has
+//! shaping examples in `std/examples/` that double as its tests.
+
+/// The name of every module and its source
+const MODULES: &[(&str, &str)] = &[
+    ("nat", include_str!("../std/nat.noq")),
+    ("bool", include_str!("../std/bool.noq")),
+    ("list", include_str!("../std/list.noq")),
+    ("pair", include_str!("../std/pair.noq")),
+    ("group", include_str!("../std/group.noq")),
+    ("ring", include_str!("../std/ring.noq")),
+];
+
+pub fn source(name: &str) -> Option<&'static str> {
+    MODULES.iter().find(|(module, _)| *module == name).map(|(_, source)| *source)
+}
+
+pub fn names() -> impl Iterator<Item=&'static str> {
+    MODULES.iter().map(|(name, _)| *name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::Context;
+
+    #[test]
+    pub fn examples() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("std").join("examples");
+        for name in super::names() {
+            let path = dir.join(format!("{}.noq", name)).display().to_string();
+            let source = fs::read_to_string(&path).unwrap_or_else(|err| panic!("{}: {}", path, err));
+            let mut context = Context::default();
+            if let Err(err) = context.run_script(&source, Some(&path)) {
+                panic!("{}: {:?}", err.loc(), err);
+            }
+            assert!(!context.is_shaping(), "{} leaves a shaping unfinished", path);
+        }
+    }
+}
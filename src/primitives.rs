@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use super::Expr;
+use super::bigint::BigInt;
+
+/// Built-in function evaluated natively once all of its arguments are numbers.
+/// Returns `None` if it is not defined for the given arguments.
+pub type Primitive = fn(&[BigInt]) -> Option<Expr>;
+
+pub struct Primitives(HashMap<String, Primitive>);
+
+fn binary(args: &[BigInt]) -> Option<(&BigInt, &BigInt)> {
+    match args {
+        [a, b] => Some((a, b)),
+        _ => None,
+    }
+}
+
+fn boolean(value: bool) -> Expr {
+    Expr::Sym(if value { "true" } else { "false" }.to_string())
+}
+
+impl Primitives {
+    /// No built-in functions at all
+    pub fn none() -> Self {
+        Self(HashMap::new())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Primitive> {
+        self.0.get(name)
+    }
+
+    /// Evaluates `expr` if it is an application of a primitive to number
+    /// literals. Peano numerals like `s(0)` are terms of their own, rules
+    /// rewrite them.
+    pub fn eval(&self, expr: &Expr) -> Option<Expr> {
+        let Expr::Fun(name, args) = expr else {
+            return None
+        };
+        let primitive = self.get(name)?;
+        let args = args.iter()
+            .map(|arg| match arg {
+                Expr::Num(n) => Some(n.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        primitive(&args)
+    }
+}
+
+impl Default for Primitives {
+    fn default() -> Self {
+        let primitives: [(&str, Primitive); 11] = [
+            ("+", |args| binary(args).map(|(a, b)| Expr::Num(a + b))),
+            ("-", |args| binary(args).map(|(a, b)| Expr::Num(a - b))),
+            ("*", |args| binary(args).map(|(a, b)| Expr::Num(a * b))),
+            ("div", |args| binary(args).and_then(|(a, b)| a.div_mod_floor(b)).map(|(q, _)| Expr::Num(q))),
+            ("mod", |args| binary(args).and_then(|(a, b)| a.div_mod_floor(b)).map(|(_, r)| Expr::Num(r))),
+            ("==", |args| binary(args).map(|(a, b)| boolean(a == b))),
+            ("!=", |args| binary(args).map(|(a, b)| boolean(a != b))),
+            ("<", |args| binary(args).map(|(a, b)| boolean(a < b))),
+            ("<=", |args| binary(args).map(|(a, b)| boolean(a <= b))),
+            (">", |args| binary(args).map(|(a, b)| boolean(a > b))),
+            (">=", |args| binary(args).map(|(a, b)| boolean(a >= b))),
+        ];
+        Self(primitives.into_iter().map(|(name, primitive)| (name.to_string(), primitive)).collect())
+    }
+}
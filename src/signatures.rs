@@ -0,0 +1,98 @@
+//! Signatures of function symbols. Arities are either declared with `sig` or
+//! inferred from the first use of the symbol, every later use has to agree.
+//! Sorts are only ever declared, symbols without them fit anywhere.
+
+use std::collections::HashMap;
+use std::iter::Peekable;
+
+use super::{Error, Expr, Notation};
+use super::lexer::{Loc, Token};
+
+pub type Sort = String;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub arity: usize,
+    /// The sorts of the arguments and of the result
+    pub sorts: Option<(Vec<Sort>, Sort)>,
+    /// Where the symbol was declared or first used
+    pub loc: Loc,
+}
+
+/// The sorts of the variables of a rule or a goal, inferred from the first
+/// position that expects one
+pub type VarSorts = HashMap<String, Sort>;
+
+#[derive(Debug, Clone, Default)]
+pub struct Signatures(HashMap<String, Signature>);
+
+impl Signatures {
+    /// Declares the arity of `name`, which has to agree with its uses so far,
+    /// and optionally its sorts, which replace the ones declared before
+    pub fn declare(&mut self, name: String, arity: usize, sorts: Option<(Vec<Sort>, Sort)>, loc: Loc) -> Result<(), Error> {
+        let signature = self.use_symbol(name, arity, loc)?;
+        if sorts.is_some() {
+            signature.sorts = sorts;
+        }
+        Ok(())
+    }
+
+    fn use_symbol(&mut self, name: String, arity: usize, loc: Loc) -> Result<&mut Signature, Error> {
+        if let Some(signature) = self.0.get(&name) {
+            if signature.arity != arity {
+                return Err(Error::ArityMismatch(name, signature.arity, arity, loc, signature.loc.clone()))
+            }
+        }
+        Ok(self.0.entry(name).or_insert(Signature {arity, sorts: None, loc}))
+    }
+
+    /// Checks the symbols of `expr`, whose nodes are located by `locs` in
+    /// preorder, and infers the arities of the ones not seen yet. Returns the
+    /// sort of `expr` if it has one, which has to be `expected` if that is given.
+    pub fn check(&mut self, expr: &Expr, locs: &mut impl Iterator<Item=Loc>, vars: &mut VarSorts, expected: Option<&Sort>) -> Result<Option<Sort>, Error> {
+        let loc = locs.next().expect("Every node of a parsed expression is located");
+        let sort = match expr {
+            Expr::Var(name) => match vars.get(name) {
+                Some(sort) => Some(sort.clone()),
+                None => {
+                    if let Some(expected) = expected {
+                        vars.insert(name.clone(), expected.clone());
+                    }
+                    expected.cloned()
+                }
+            },
+            Expr::Sym(name) => self.use_symbol(name.clone(), 0, loc.clone())?.sorts.clone().map(|(_, sort)| sort),
+            Expr::Fun(name, args) => {
+                let sorts = self.use_symbol(name.clone(), args.len(), loc.clone())?.sorts.clone();
+                for (i, arg) in args.iter().enumerate() {
+                    let arg_sort = sorts.as_ref().map(|(arg_sorts, _)| &arg_sorts[i]);
+                    self.check(arg, locs, vars, arg_sort)?;
+                }
+                sorts.map(|(_, sort)| sort)
+            }
+            Expr::Num(_) => None,
+        };
+        match (sort, expected) {
+            (Some(sort), Some(expected)) if &sort != expected => {
+                Err(Error::SortMismatch(sort, expected.clone(), loc))
+            }
+            (sort, _) => Ok(sort),
+        }
+    }
+
+    /// Parses an expression and [checks](Signatures::check) it
+    pub fn parse(&mut self, lexer: &mut Peekable<impl Iterator<Item=Token>>, notation: &Notation, vars: &mut VarSorts, expected: Option<&Sort>) -> Result<(Expr, Option<Sort>), Error> {
+        let mut locs = Vec::new();
+        let expr = Expr::parse_located(lexer, notation, &mut locs)?;
+        let sort = self.check(&expr, &mut locs.into_iter(), vars, expected)?;
+        Ok((expr, sort))
+    }
+
+    /// Like [`Signatures::parse`], but an operator that no operand follows ends the expression and is returned too
+    pub fn parse_trailing(&mut self, lexer: &mut Peekable<impl Iterator<Item=Token>>, notation: &Notation, vars: &mut VarSorts, expected: Option<&Sort>) -> Result<(Expr, Option<Sort>, Option<Token>), Error> {
+        let mut locs = Vec::new();
+        let (expr, trailing) = Expr::parse_trailing(lexer, notation, &mut locs)?;
+        let sort = self.check(&expr, &mut locs.into_iter(), vars, expected)?;
+        Ok((expr, sort, trailing))
+    }
+}
@@ -0,0 +1,264 @@
+//! Termination checking with reduction orderings. A set of rules terminates if
+//! some reduction ordering makes the head of every rule greater than its body.
+//! Both orderings here are parameterized by a precedence on the function
+//! symbols, which is searched for instead of being given by the user.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use super::{Expr, Rule};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    /// Lexicographic path ordering
+    Lpo,
+    /// Knuth-Bendix ordering where every symbol and variable weighs 1
+    Kbo,
+}
+
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Order::Lpo => write!(f, "lpo"),
+            Order::Kbo => write!(f, "kbo"),
+        }
+    }
+}
+
+/// A strict order on function symbols, given by the pairs `f > g` it was built
+/// from. Constants and numbers are symbols without arguments.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Precedence(Vec<(String, String)>);
+
+impl Precedence {
+    pub fn greater(&self, f: &str, g: &str) -> bool {
+        let mut visited = vec![f];
+        let mut i = 0;
+        while i < visited.len() {
+            for (a, b) in &self.0 {
+                if a == visited[i] && !visited.contains(&b.as_str()) {
+                    if b == g {
+                        return true
+                    }
+                    visited.push(b);
+                }
+            }
+            i += 1;
+        }
+        false
+    }
+}
+
+impl fmt::Display for Precedence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "(any)")
+        }
+        for (i, (a, b)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} > {}", a, b)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the precedence has to satisfy for one term to be greater than another
+#[derive(Debug, Clone, PartialEq)]
+enum Constraint {
+    True,
+    False,
+    Greater(String, String),
+    All(Vec<Constraint>),
+    Any(Vec<Constraint>),
+}
+
+fn all(constraints: impl IntoIterator<Item=Constraint>) -> Constraint {
+    let mut result = Vec::new();
+    for constraint in constraints {
+        match constraint {
+            Constraint::True => {},
+            Constraint::False => return Constraint::False,
+            constraint => result.push(constraint),
+        }
+    }
+    if result.is_empty() { Constraint::True } else { Constraint::All(result) }
+}
+
+fn any(constraints: impl IntoIterator<Item=Constraint>) -> Constraint {
+    let mut result = Vec::new();
+    for constraint in constraints {
+        match constraint {
+            Constraint::True => return Constraint::True,
+            Constraint::False => {},
+            constraint => result.push(constraint),
+        }
+    }
+    if result.is_empty() { Constraint::False } else { Constraint::Any(result) }
+}
+
+/// The function symbol at the root of `expr` and its arguments, `None` for variables
+fn root(expr: &Expr) -> Option<(String, &[Expr])> {
+    match expr {
+        Expr::Sym(name) => Some((name.clone(), &[])),
+        Expr::Num(n) => Some((n.to_string(), &[])),
+        Expr::Fun(name, args) => Some((name.clone(), args)),
+        Expr::Var(_) => None,
+    }
+}
+
+/// Compares the first pair of arguments that differ, a longer list of
+/// otherwise equal arguments is greater.
+fn lex(ss: &[Expr], ts: &[Expr], gt: fn(&Expr, &Expr) -> Constraint) -> Constraint {
+    match ss.iter().zip(ts).find(|(s, t)| s != t) {
+        Some((s, t)) => gt(s, t),
+        None => if ss.len() > ts.len() { Constraint::True } else { Constraint::False },
+    }
+}
+
+fn lpo_greater(s: &Expr, t: &Expr) -> Constraint {
+    if s == t {
+        return Constraint::False
+    }
+    let Some((f, ss)) = root(s) else {
+        return Constraint::False
+    };
+    let Some((g, ts)) = root(t) else {
+        let Expr::Var(x) = t else { unreachable!() };
+        return if s.vars().contains(x) { Constraint::True } else { Constraint::False }
+    };
+    let some_argument_is_greater = any(ss.iter().map(|si| {
+        if si == t { Constraint::True } else { lpo_greater(si, t) }
+    }));
+    let greater_than_arguments = || all(ts.iter().map(|tj| lpo_greater(s, tj)));
+    let by_root = if f == g {
+        all([greater_than_arguments(), lex(ss, ts, lpo_greater)])
+    } else {
+        all([Constraint::Greater(f, g), greater_than_arguments()])
+    };
+    any([some_argument_is_greater, by_root])
+}
+
+fn var_counts(expr: &Expr, counts: &mut HashMap<String, usize>) {
+    match expr {
+        Expr::Var(name) => *counts.entry(name.clone()).or_default() += 1,
+        Expr::Fun(_, args) => args.iter().for_each(|arg| var_counts(arg, counts)),
+        Expr::Sym(_) | Expr::Num(_) => {},
+    }
+}
+
+fn weight(expr: &Expr) -> usize {
+    match expr {
+        Expr::Fun(_, args) => 1 + args.iter().map(weight).sum::<usize>(),
+        Expr::Sym(_) | Expr::Num(_) | Expr::Var(_) => 1,
+    }
+}
+
+fn kbo_greater(s: &Expr, t: &Expr) -> Constraint {
+    let (mut s_vars, mut t_vars) = (HashMap::new(), HashMap::new());
+    var_counts(s, &mut s_vars);
+    var_counts(t, &mut t_vars);
+    if t_vars.iter().any(|(x, n)| s_vars.get(x).unwrap_or(&0) < n) {
+        return Constraint::False
+    }
+    match weight(s).cmp(&weight(t)) {
+        std::cmp::Ordering::Greater => Constraint::True,
+        std::cmp::Ordering::Less => Constraint::False,
+        std::cmp::Ordering::Equal => match (root(s), root(t)) {
+            (Some((f, ss)), Some((g, ts))) => {
+                if f == g { lex(ss, ts, kbo_greater) } else { Constraint::Greater(f, g) }
+            }
+            // Equal weights and the variable condition leave no room for variables here
+            _ => Constraint::False,
+        }
+    }
+}
+
+/// Extends `precedence` to satisfy all the `pending` constraints, backtracking
+/// over the alternatives of `Any`.
+fn solve(pending: &[&Constraint], precedence: &Precedence) -> Option<Precedence> {
+    let Some((first, rest)) = pending.split_first() else {
+        return Some(precedence.clone())
+    };
+    match first {
+        Constraint::True => solve(rest, precedence),
+        Constraint::False => None,
+        Constraint::Greater(f, g) => {
+            if precedence.greater(f, g) {
+                solve(rest, precedence)
+            } else if f == g || precedence.greater(g, f) {
+                None
+            } else {
+                let mut extended = precedence.clone();
+                extended.0.push((f.clone(), g.clone()));
+                solve(rest, &extended)
+            }
+        }
+        Constraint::All(constraints) => {
+            let pending: Vec<&Constraint> = constraints.iter().chain(rest.iter().copied()).collect();
+            solve(&pending, precedence)
+        }
+        Constraint::Any(constraints) => constraints.iter().find_map(|constraint| {
+            let pending: Vec<&Constraint> = [constraint].into_iter().chain(rest.iter().copied()).collect();
+            solve(&pending, precedence)
+        }),
+    }
+}
+
+/// Searches for a precedence under which `order` orients every rule from head
+/// to body. Fails with the name of the first rule that can't be oriented
+/// together with the ones before it. Conditions of rules are ignored, they
+/// only ever take rewrites away.
+pub fn check<'a>(order: Order, rules: &[(&'a str, &Rule)]) -> Result<Precedence, &'a str> {
+    let greater = match order {
+        Order::Lpo => lpo_greater,
+        Order::Kbo => kbo_greater,
+    };
+    let mut constraints = Vec::new();
+    let mut precedence = Precedence::default();
+    for (name, rule) in rules {
+        constraints.push(greater(&rule.head, &rule.body));
+        let pending: Vec<&Constraint> = constraints.iter().collect();
+        precedence = solve(&pending, &Precedence::default()).ok_or(*name)?;
+    }
+    Ok(precedence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::rule;
+
+    #[test]
+    pub fn lpo_orients_arithmetic() {
+        let add0 = rule("add(0, Y)", "Y");
+        let add = rule("add(s(X), Y)", "s(add(X, Y))");
+        let mul0 = rule("mul(0, Y)", "0");
+        let mul = rule("mul(s(X), Y)", "add(Y, mul(X, Y))");
+        let rules = [("add0", &add0), ("add", &add), ("mul0", &mul0), ("mul", &mul)];
+
+        let precedence = check(Order::Lpo, &rules).unwrap();
+        assert!(precedence.greater("add", "s") && precedence.greater("mul", "add"));
+        // The body of `mul` duplicates `Y`, which no Knuth-Bendix ordering allows
+        assert_eq!(check(Order::Kbo, &rules), Err("mul"));
+        assert!(check(Order::Kbo, &rules[..3]).is_ok());
+    }
+
+    #[test]
+    pub fn orders_reject_cycles() {
+        let comm = rule("add(X, Y)", "add(Y, X)");
+        let unbound = rule("f(X)", "g(Y)");
+        let fg = rule("f(X)", "g(X)");
+        let fh = rule("f(X)", "h(X)");
+        let gh = rule("g(h(X))", "f(X)");
+        for order in [Order::Lpo, Order::Kbo] {
+            assert_eq!(check(order, &[("comm", &comm)]), Err("comm"));
+            assert_eq!(check(order, &[("unbound", &unbound)]), Err("unbound"));
+        }
+        // `gh` needs `h > f` or `g > f` under LPO, but KBO orients it by weight alone
+        let rules = [("fg", &fg), ("fh", &fh), ("gh", &gh)];
+        assert_eq!(check(Order::Lpo, &rules), Err("gh"));
+        assert_eq!(check(Order::Kbo, &rules).map(|precedence| precedence.to_string()), Ok("f > g, f > h".to_string()));
+    }
+}
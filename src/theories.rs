@@ -0,0 +1,193 @@
+//! Equational theories of function symbols. Applications of associative
+//! symbols are kept flattened, `f(f(a, b), c)` is `f(a, b, c)`, so matching
+//! modulo a theory comes down to the ways of splitting the arguments of the
+//! value among the arguments of the pattern.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+use super::Expr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Theory {
+    pub assoc: bool,
+    pub comm: bool,
+}
+
+impl Theory {
+    pub const ASSOC: Theory = Theory {assoc: true, comm: false};
+    pub const COMM: Theory = Theory {assoc: false, comm: true};
+    pub const AC: Theory = Theory {assoc: true, comm: true};
+}
+
+impl fmt::Display for Theory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.assoc, self.comm) {
+            (true, true) => write!(f, "ac"),
+            (true, false) => write!(f, "assoc"),
+            (false, true) => write!(f, "comm"),
+            (false, false) => write!(f, "free"),
+        }
+    }
+}
+
+/// The theories declared for function symbols, the rest are free
+#[derive(Debug, Default)]
+pub struct Theories(HashMap<String, Theory>);
+
+impl Theories {
+    pub fn get(&self, name: &str) -> Theory {
+        self.0.get(name).copied().unwrap_or_default()
+    }
+
+    /// Adds `theory` to the ones already declared for `name`, so `assoc` and `comm` together make `ac`
+    pub fn declare(&mut self, name: String, theory: Theory) {
+        let declared = self.0.entry(name).or_default();
+        declared.assoc |= theory.assoc;
+        declared.comm |= theory.comm;
+    }
+
+    fn normalize(&self, expr: &Expr, sort: bool) -> Expr {
+        let Expr::Fun(name, args) = expr else {
+            return expr.clone()
+        };
+        let theory = self.get(name);
+        let mut new_args = Vec::new();
+        for arg in args {
+            match self.normalize(arg, sort) {
+                Expr::Fun(arg_name, arg_args) if theory.assoc && &arg_name == name => new_args.extend(arg_args),
+                arg => new_args.push(arg),
+            }
+        }
+        if sort && theory.comm {
+            new_args.sort();
+        }
+        Expr::Fun(name.clone(), new_args)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&str, Theory)> {
+        self.0.iter().map(|(name, theory)| (name.as_str(), *theory))
+    }
+
+    /// The opposite of [`flatten`](Theories::flatten): applications of
+    /// associative symbols to more than two arguments are nested to the left
+    pub fn nest(&self, expr: &Expr) -> Expr {
+        let Expr::Fun(name, args) = expr else {
+            return expr.clone()
+        };
+        let mut args = args.iter().map(|arg| self.nest(arg));
+        if !self.get(name).assoc || args.len() <= 2 {
+            return Expr::Fun(name.clone(), args.collect())
+        }
+        let first = args.next().expect("More than two arguments");
+        args.fold(first, |nested, arg| Expr::Fun(name.clone(), vec![nested, arg]))
+    }
+
+    /// Splices nested applications of associative symbols into their parents
+    pub fn flatten<'a>(&self, expr: &'a Expr) -> Cow<'a, Expr> {
+        if self.0.values().any(|theory| theory.assoc) {
+            Cow::Owned(self.normalize(expr, false))
+        } else {
+            Cow::Borrowed(expr)
+        }
+    }
+
+    /// Flattened and with the arguments of commutative symbols sorted, equal
+    /// for all the expressions that are equal modulo the theories
+    pub fn canonical(&self, expr: &Expr) -> Expr {
+        self.normalize(expr, true)
+    }
+
+    pub fn equal(&self, a: &Expr, b: &Expr) -> bool {
+        a == b || (!self.0.is_empty() && self.canonical(a) == self.canonical(b))
+    }
+
+    /// The ways of matching `values` flattened arguments of `name` with
+    /// `patterns` ones: for every argument of the pattern, the indices of the
+    /// arguments of the value it stands for. Several of them stand for an
+    /// application of `name` to all of them.
+    pub fn splits(&self, name: &str, patterns: usize, values: usize) -> Vec<Vec<Vec<usize>>> {
+        fn in_order(patterns: usize, values: usize, start: usize, split: &mut Vec<Vec<usize>>, splits: &mut Vec<Vec<Vec<usize>>>) {
+            if split.len() + 1 == patterns {
+                split.push((start..values).collect());
+                splits.push(split.clone());
+                split.pop();
+                return
+            }
+            // Leave at least one value for each of the remaining patterns
+            for end in start + 1..=values + split.len() + 1 - patterns {
+                split.push((start..end).collect());
+                in_order(patterns, values, end, split, splits);
+                split.pop();
+            }
+        }
+
+        fn in_any_order(values: usize, next: usize, split: &mut Vec<Vec<usize>>, splits: &mut Vec<Vec<Vec<usize>>>) {
+            let empty = split.iter().filter(|group| group.is_empty()).count();
+            if empty > values - next {
+                return
+            }
+            if next == values {
+                splits.push(split.clone());
+                return
+            }
+            for i in 0..split.len() {
+                split[i].push(next);
+                in_any_order(values, next + 1, split, splits);
+                split[i].pop();
+            }
+        }
+
+        let theory = self.get(name);
+        let mut splits = Vec::new();
+        if patterns == 0 || values < patterns || (!theory.assoc && values != patterns) {
+            if patterns == values {
+                splits.push(vec![]);
+            }
+            return splits
+        }
+        match (theory.assoc, theory.comm) {
+            (_, true) => in_any_order(values, 0, &mut vec![vec![]; patterns], &mut splits),
+            (true, false) => in_order(patterns, values, 0, &mut vec![], &mut splits),
+            (false, false) => splits.push((0..values).map(|i| vec![i]).collect()),
+        }
+        splits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn splits() {
+        let mut theories = Theories::default();
+        theories.declare("f".to_string(), Theory::ASSOC);
+        theories.declare("g".to_string(), Theory::COMM);
+        theories.declare("h".to_string(), Theory::ASSOC);
+        theories.declare("h".to_string(), Theory::COMM);
+        assert_eq!(theories.get("h"), Theory::AC);
+
+        assert_eq!(theories.splits("free", 2, 2), [[vec![0], vec![1]]]);
+        assert!(theories.splits("free", 2, 3).is_empty());
+        assert_eq!(theories.splits("f", 2, 3), [[vec![0], vec![1, 2]], [vec![0, 1], vec![2]]]);
+        assert_eq!(theories.splits("g", 2, 2), [[vec![0], vec![1]], [vec![1], vec![0]]]);
+        assert!(theories.splits("g", 2, 3).is_empty());
+        // Every value to either pattern, as long as both get some
+        assert_eq!(theories.splits("h", 2, 3).len(), 6);
+        assert!(theories.splits("h", 3, 2).is_empty());
+    }
+
+    #[test]
+    pub fn nest() {
+        let mut theories = Theories::default();
+        theories.declare("f".to_string(), Theory::ASSOC);
+        let x = |name: &str| Expr::Sym(name.to_string());
+        let f = |args: Vec<Expr>| Expr::Fun("f".to_string(), args);
+        let flat = f(vec![x("a"), f(vec![x("b"), x("c"), x("d")])]);
+        let nested = theories.nest(&flat);
+        assert_eq!(nested, f(vec![x("a"), f(vec![f(vec![x("b"), x("c")]), x("d")])]));
+        assert_eq!(theories.flatten(&nested).into_owned(), f(vec![x("a"), x("b"), x("c"), x("d")]));
+    }
+}
@@ -0,0 +1,110 @@
+//! Syntactic first-order unification. Unlike [`pattern_match`](super::pattern_match),
+//! which only binds the variables of the pattern, both sides may contain variables.
+
+use std::fmt;
+
+use super::{Bindings, DisplayWithNotation, Expr, Notation, BigInt, substitute_bindings};
+
+/// Variables and the expressions they stand for. The substitutions built by
+/// [`unify`] are idempotent: no bound variable occurs in the expressions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Substitution(Bindings);
+
+impl Substitution {
+    pub fn apply(&self, expr: &Expr) -> Expr {
+        substitute_bindings(&self.0, expr)
+    }
+
+    /// Binds `var` to `value` in all the existing bindings as well
+    fn bind(&mut self, var: String, value: Expr) {
+        let binding = Bindings::from([(var.clone(), value.clone())]);
+        for bound_value in self.0.values_mut() {
+            *bound_value = substitute_bindings(&binding, bound_value);
+        }
+        self.0.insert(var, value);
+    }
+}
+
+impl DisplayWithNotation for Substitution {
+    fn fmt_with(&self, f: &mut fmt::Formatter, notation: &Notation) -> fmt::Result {
+        let mut vars: Vec<&String> = self.0.keys().collect();
+        vars.sort();
+        write!(f, "{{")?;
+        for (i, var) in vars.into_iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} := {}", var, self.0[var].with(notation))?;
+        }
+        write!(f, "}}")
+    }
+}
+
+/// Why two expressions don't unify
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    /// Subterms with different symbols at the root
+    Clash(Expr, Expr),
+    /// The variable would have to contain itself
+    Occurs(String, Expr),
+}
+
+impl DisplayWithNotation for Mismatch {
+    fn fmt_with(&self, f: &mut fmt::Formatter, notation: &Notation) -> fmt::Result {
+        match self {
+            Mismatch::Clash(a, b) => write!(f, "{} and {} can't be made equal", a.with(notation), b.with(notation)),
+            Mismatch::Occurs(var, expr) => write!(f, "{} occurs in {}", var, expr.with(notation)),
+        }
+    }
+}
+
+/// The most general substitution that makes `a` and `b` equal. Numbers unify
+/// with Peano numerals like they do in patterns.
+pub fn unify(a: &Expr, b: &Expr) -> Result<Substitution, Mismatch> {
+    use Expr::*;
+    let mut substitution = Substitution::default();
+    let mut equations = vec![(a.clone(), b.clone())];
+    while let Some((a, b)) = equations.pop() {
+        let a = substitution.apply(&a);
+        let b = substitution.apply(&b);
+        match (&a, &b) {
+            _ if a == b => {},
+            (Var(name), value) | (value, Var(name)) => {
+                if value.vars().contains(name) {
+                    return Err(Mismatch::Occurs(name.clone(), value.clone()))
+                }
+                substitution.bind(name.clone(), value.clone());
+            }
+            (Fun(a_name, a_args), Fun(b_name, b_args)) if a_name == b_name && a_args.len() == b_args.len() => {
+                equations.extend(a_args.iter().cloned().zip(b_args.iter().cloned()).rev());
+            }
+            (Num(n), Fun(name, args)) | (Fun(name, args), Num(n)) if name == "s" && args.len() == 1 && n > &BigInt::zero() => {
+                equations.push((Num(n - &BigInt::from(1)), args[0].clone()));
+            }
+            _ => return Err(Mismatch::Clash(a, b)),
+        }
+    }
+    Ok(substitution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::expr;
+
+    #[test]
+    pub fn most_general_unifier() {
+        let substitution = unify(&expr("f(X, g(Y))"), &expr("f(g(Z), X)")).unwrap();
+        assert_eq!(substitution.apply(&expr("f(X, g(Y))")), expr("f(g(Z), g(Z))"));
+        assert_eq!(substitution.with(&Notation::default()).to_string(), "{X := g(Z), Y := Z}");
+        assert_eq!(unify(&expr("s(s(X))"), &expr("3")).unwrap().apply(&expr("X")), expr("1"));
+        assert_eq!(unify(&expr("f(a, b)"), &expr("f(a, b)")), Ok(Substitution::default()));
+    }
+
+    #[test]
+    pub fn mismatches() {
+        assert_eq!(unify(&expr("f(X)"), &expr("f(s(X))")), Err(Mismatch::Occurs("X".to_string(), expr("s(X)"))));
+        assert_eq!(unify(&expr("f(X, a)"), &expr("f(b, g(X))")), Err(Mismatch::Clash(expr("a"), expr("g(b)"))));
+        assert_eq!(unify(&expr("f(a)"), &expr("f(a, b)")), Err(Mismatch::Clash(expr("f(a)"), expr("f(a, b)"))));
+    }
+}